@@ -0,0 +1,232 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use libunftp::notification::{DataEvent, DataListener, EventMeta, PresenceEvent, PresenceListener};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::error;
+use crate::sessions::SessionRegistry;
+
+/// One line of the audit log
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    time: String,
+    event: &'a str,
+    user: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<&'a str>,
+    /// New name for renames
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    /// Transfer time, or session length for logouts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u128>,
+    /// libunftp's id for the session, to group a session's records
+    session: &'a str,
+}
+
+#[derive(Debug)]
+struct Login {
+    remote_ip: Option<IpAddr>,
+    at: Instant,
+}
+
+/// Append-only JSON lines log of logins, logouts and file operations
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    file: Arc<Mutex<File>>,
+    sessions: SessionRegistry,
+    /// Remote address and login time per trace id, kept here so logouts of kicked sessions are complete
+    logins: Arc<Mutex<HashMap<String, Login>>>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path, sessions: SessionRegistry) -> Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create '{}'", dir.display()))?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open audit log '{}'", path.display()))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Arc::new(Mutex::new(file)),
+            sessions,
+            logins: Arc::default(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    async fn write(&self, record: Record<'_>) {
+        let line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to encode audit record: {}", e);
+                return;
+            }
+        };
+
+        // A slow disk must not stall the async worker threads, the lock keeps lines whole and in order
+        let file = self.file.clone();
+        let written = tokio::task::spawn_blocking(move || {
+            let mut file = file.lock().unwrap();
+            writeln!(file, "{}", line).and_then(|_| file.flush())
+        })
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+        if let Err(e) = written {
+            error!("Failed to write audit log '{}': {}", self.path.display(), e);
+        }
+    }
+}
+
+#[async_trait]
+impl PresenceListener for AuditLog {
+    async fn receive_presence_event(&self, event: PresenceEvent, meta: EventMeta) {
+        let (event, remote_ip, duration) = match event {
+            PresenceEvent::LoggedIn => {
+                let remote_ip = self.sessions.remote_of(&meta.trace_id);
+                self.logins.lock().unwrap().insert(meta.trace_id.clone(), Login { remote_ip, at: Instant::now() });
+                ("login", remote_ip, None)
+            }
+            PresenceEvent::LoggedOut => match self.logins.lock().unwrap().remove(&meta.trace_id) {
+                Some(login) => ("logout", login.remote_ip, Some(login.at.elapsed())),
+                None => ("logout", None, None),
+            },
+        };
+
+        self.write(Record {
+            time: timestamp(SystemTime::now()),
+            event,
+            user: &meta.username,
+            remote_ip,
+            path: None,
+            to: None,
+            size: None,
+            duration_ms: duration.map(|d| d.as_millis()),
+            session: &meta.trace_id,
+        })
+        .await;
+    }
+}
+
+#[async_trait]
+impl DataListener for AuditLog {
+    async fn receive_data_event(&self, event: DataEvent, meta: EventMeta) {
+        let (event, path, to, size) = match &event {
+            DataEvent::Got { path, bytes } => ("download", path, None, Some(*bytes)),
+            DataEvent::Put { path, bytes } => ("upload", path, None, Some(*bytes)),
+            DataEvent::Deleted { path } => ("delete", path, None, None),
+            DataEvent::MadeDir { path } => ("mkdir", path, None, None),
+            DataEvent::RemovedDir { path } => ("rmdir", path, None, None),
+            DataEvent::Renamed { from, to } => ("rename", from, Some(to.as_str()), None),
+        };
+        let duration = match size {
+            Some(_) => self.sessions.last_transfer_duration(&meta.trace_id),
+            None => None,
+        };
+        let remote_ip = self
+            .logins
+            .lock()
+            .unwrap()
+            .get(&meta.trace_id)
+            .and_then(|login| login.remote_ip);
+
+        self.write(Record {
+            time: timestamp(SystemTime::now()),
+            event,
+            user: &meta.username,
+            remote_ip,
+            path: Some(path),
+            to,
+            size,
+            duration_ms: duration.map(|d| d.as_millis()),
+            session: &meta.trace_id,
+        })
+        .await;
+    }
+}
+
+/// Format a time as RFC 3339 in UTC with millisecond precision, e.g. `2024-05-01T12:34:56.789Z`
+pub fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let seconds_of_day = seconds % 86_400;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Convert days since 1970-01-01 to a (year, month, day) date, using Howard Hinnant's algorithm
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(seconds: u64, millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds) + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn converts_days_to_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11_017), (2000, 3, 1));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(19_783), (2024, 3, 1));
+        // 2100 is not a leap year
+        assert_eq!(civil_from_days(47_540), (2100, 2, 28));
+        assert_eq!(civil_from_days(47_541), (2100, 3, 1));
+        assert_eq!(civil_from_days(47_846), (2100, 12, 31));
+        assert_eq!(civil_from_days(47_847), (2101, 1, 1));
+    }
+
+    #[test]
+    fn formats_timestamps_in_utc() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(timestamp(at(951_782_400, 1)), "2000-02-29T00:00:00.001Z");
+        assert_eq!(timestamp(at(1_709_251_199, 999)), "2024-02-29T23:59:59.999Z");
+        assert_eq!(timestamp(at(4_107_542_400 + 45_296, 789)), "2100-03-01T12:34:56.789Z");
+    }
+}
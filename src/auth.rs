@@ -0,0 +1,232 @@
+use async_trait::async_trait;
+use libunftp::auth::{AuthenticationError, Authenticator, Credentials};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{info, warn};
+use crate::sessions::SessionRegistry;
+use crate::users::{normalize_path, verify_password, FtpUser, UserAccount};
+
+/// Login names accepted for anonymous access
+const ANONYMOUS_USERS: [&str; 2] = ["anonymous", "ftp"];
+
+/// Opt-in anonymous login settings
+#[derive(Debug, Clone, Default)]
+pub struct AnonymousAccess {
+    pub read_only: bool,
+    /// Subdirectory of the served root anonymous users are confined to
+    pub home: Option<PathBuf>,
+}
+
+impl AnonymousAccess {
+    pub fn new(read_only: bool, home: Option<PathBuf>) -> anyhow::Result<Self> {
+        let home = match home {
+            Some(home) => {
+                let home = normalize_path(&home)
+                    .ok_or_else(|| anyhow::anyhow!("Anonymous directory '{}' escapes the served directory", home.display()))?;
+                (!home.as_os_str().is_empty()).then_some(home)
+            }
+            None => None,
+        };
+        Ok(Self { read_only, home })
+    }
+
+    /// Description for the server info banner
+    pub fn describe(&self) -> String {
+        let mut description = format!("users '{}' with any password", ANONYMOUS_USERS.join("' or '"));
+        description.push_str(if self.read_only { ", read-only" } else { ", read-write" });
+        if let Some(home) = &self.home {
+            description.push_str(&format!(", limited to /{}", home.display()));
+        }
+        description
+    }
+}
+
+/// How an account's password is checked
+#[derive(Debug, Clone)]
+enum Secret {
+    /// Password given directly on the command line or in the GUI
+    Plain(String),
+    /// bcrypt hash from the users file
+    Hash(String),
+}
+
+#[derive(Debug, Clone)]
+struct Account {
+    secret: Secret,
+    user: FtpUser,
+}
+
+/// Authenticator that only accepts the configured accounts
+#[derive(Debug, Clone)]
+pub struct SixFtpAuthenticator {
+    accounts: HashMap<String, Account>,
+    anonymous: Option<AnonymousAccess>,
+    sessions: SessionRegistry,
+}
+
+impl SixFtpAuthenticator {
+    /// Single account with full access to the served directory
+    pub fn new(username: &str, password: &str) -> Self {
+        let account = Account {
+            secret: Secret::Plain(password.to_string()),
+            user: FtpUser {
+                username: username.to_string(),
+                home: None,
+                read: true,
+                write: true,
+                session: None,
+            },
+        };
+
+        Self {
+            accounts: HashMap::from([(username.to_string(), account)]),
+            anonymous: None,
+            sessions: SessionRegistry::default(),
+        }
+    }
+
+    /// Accounts loaded from a users file
+    pub fn from_accounts(users: &[UserAccount]) -> Self {
+        let accounts = users
+            .iter()
+            .map(|u| {
+                let account = Account {
+                    secret: Secret::Hash(u.password_hash.clone()),
+                    user: FtpUser {
+                        username: u.username.clone(),
+                        home: u.home.clone(),
+                        read: u.read,
+                        write: u.write,
+                        session: None,
+                    },
+                };
+                (u.username.clone(), account)
+            })
+            .collect();
+
+        Self {
+            accounts,
+            anonymous: None,
+            sessions: SessionRegistry::default(),
+        }
+    }
+
+    /// Also accept anonymous logins
+    pub fn with_anonymous(mut self, anonymous: Option<AnonymousAccess>) -> Self {
+        self.anonymous = anonymous;
+        self
+    }
+
+    /// Register successful logins in `sessions`
+    pub fn with_sessions(mut self, sessions: SessionRegistry) -> Self {
+        self.sessions = sessions;
+        self
+    }
+
+    /// Register the login on `connection`, refusing it if the connection was closed meanwhile
+    fn register(&self, username: &str, creds: &Credentials, connection: u64) -> Result<u64, AuthenticationError> {
+        self.sessions.login(username, creds.source_ip, connection).map_err(|e| {
+            warn!("Refused login from {} as '{}': {}", creds.source_ip, username, e);
+            AuthenticationError::IpDisallowed
+        })
+    }
+
+    /// Check a login on control connection `connection`
+    pub async fn authenticate(
+        &self,
+        username: &str,
+        creds: &Credentials,
+        connection: u64,
+    ) -> Result<FtpUser, AuthenticationError> {
+        if self.sessions.bans().is_banned(creds.source_ip) {
+            warn!("Refused login from banned address {} as '{}'", creds.source_ip, username);
+            return Err(AuthenticationError::IpDisallowed);
+        }
+
+        let Some(account) = self.accounts.get(username) else {
+            if let Some(anonymous) = &self.anonymous
+                && ANONYMOUS_USERS.contains(&username.to_ascii_lowercase().as_str())
+            {
+                let session = self.register(username, creds, connection)?;
+                info!("Anonymous login from {} as '{}'", creds.source_ip, username);
+                return Ok(FtpUser {
+                    username: username.to_string(),
+                    home: anonymous.home.clone(),
+                    read: true,
+                    write: !anonymous.read_only,
+                    session: Some(session),
+                });
+            }
+
+            warn!("Failed login from {}: unknown user '{}'", creds.source_ip, username);
+            self.sessions.bans().record_failure(creds.source_ip);
+            return Err(AuthenticationError::BadUser);
+        };
+
+        let supplied = creds.password.as_deref().unwrap_or_default();
+        let valid = match &account.secret {
+            // Compare the full password even on mismatch so timing doesn't leak the matching prefix
+            Secret::Plain(password) => constant_time_eq(supplied.as_bytes(), password.as_bytes()),
+            Secret::Hash(hash) => {
+                // bcrypt is deliberately slow, keep it off the async worker threads
+                let supplied = supplied.to_string();
+                let hash = hash.clone();
+                tokio::task::spawn_blocking(move || verify_password(&supplied, &hash))
+                    .await
+                    .unwrap_or(false)
+            }
+        };
+
+        if !valid {
+            warn!("Failed login from {}: bad password for user '{}'", creds.source_ip, username);
+            self.sessions.bans().record_failure(creds.source_ip);
+            return Err(AuthenticationError::BadPassword);
+        }
+
+        let session = self.register(username, creds, connection)?;
+        info!("User '{}' logged in from {}", username, creds.source_ip);
+        Ok(FtpUser {
+            session: Some(session),
+            ..account.user.clone()
+        })
+    }
+}
+
+/// The shared authenticator as libunftp sees it on one control connection. libunftp talks to the
+/// server's relay rather than the client, so this supplies the client's address and ties the session
+/// to its connection.
+#[derive(Debug)]
+pub struct ConnectionAuthenticator {
+    authenticator: Arc<SixFtpAuthenticator>,
+    connection: u64,
+    remote: IpAddr,
+}
+
+impl ConnectionAuthenticator {
+    pub fn new(authenticator: Arc<SixFtpAuthenticator>, connection: u64, remote: IpAddr) -> Self {
+        Self {
+            authenticator,
+            connection,
+            remote,
+        }
+    }
+}
+
+#[async_trait]
+impl Authenticator<FtpUser> for ConnectionAuthenticator {
+    async fn authenticate(&self, username: &str, creds: &Credentials) -> Result<FtpUser, AuthenticationError> {
+        let mut creds = creds.clone();
+        creds.source_ip = self.remote;
+        self.authenticator.authenticate(username, &creds, self.connection).await
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::{info, warn};
+use crate::sessions::format_duration;
+
+/// When repeated failed logins get an address banned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoginProtection {
+    /// Failed logins within `window` that trigger a ban, 0 to never ban
+    pub max_failures: u32,
+    pub window: Duration,
+    pub ban_duration: Duration,
+}
+
+impl Default for LoginProtection {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            window: Duration::from_secs(600),
+            ban_duration: Duration::from_secs(900),
+        }
+    }
+}
+
+impl LoginProtection {
+    /// Description for the server info banner, `None` when disabled
+    pub fn describe(&self) -> Option<String> {
+        (self.max_failures > 0).then(|| {
+            format!(
+                "addresses are banned for {} after {} failed logins within {}",
+                format_duration(self.ban_duration),
+                self.max_failures,
+                format_duration(self.window)
+            )
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Ban {
+    reason: String,
+    /// `None` for bans that last until the server stops
+    until: Option<Instant>,
+}
+
+impl Ban {
+    fn is_active(&self, now: Instant) -> bool {
+        self.until.is_none_or(|until| until > now)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Bans {
+    bans: HashMap<IpAddr, Ban>,
+    /// Times of recent failed logins per address
+    failures: HashMap<IpAddr, Vec<Instant>>,
+}
+
+/// Addresses refused at login, either banned by hand or after too many failed logins
+#[derive(Debug, Clone)]
+pub struct BanList {
+    protection: LoginProtection,
+    bans: Arc<Mutex<Bans>>,
+    /// Shared with the session registry so its subscribers also see ban changes
+    changed: Arc<watch::Sender<()>>,
+}
+
+impl BanList {
+    pub fn new(protection: LoginProtection, changed: Arc<watch::Sender<()>>) -> Self {
+        Self {
+            protection,
+            bans: Arc::default(),
+            changed,
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.is_banned_at(ip, Instant::now())
+    }
+
+    fn is_banned_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let bans = self.bans.lock().unwrap();
+        bans.bans.get(&ip).is_some_and(|ban| ban.is_active(now))
+    }
+
+    /// Count a failed login from `ip` and ban it once it reaches the threshold
+    pub fn record_failure(&self, ip: IpAddr) {
+        self.record_failure_at(ip, Instant::now());
+    }
+
+    fn record_failure_at(&self, ip: IpAddr, now: Instant) {
+        let LoginProtection { max_failures, window, ban_duration } = self.protection;
+        if max_failures == 0 {
+            return;
+        }
+
+        let mut bans = self.bans.lock().unwrap();
+        let failures = bans.failures.entry(ip).or_default();
+        failures.retain(|at| now.duration_since(*at) < window);
+        failures.push(now);
+        if failures.len() < max_failures as usize {
+            return;
+        }
+
+        bans.failures.remove(&ip);
+        bans.bans.insert(ip, Ban {
+            reason: format!("{} failed logins", max_failures),
+            until: Some(now + ban_duration),
+        });
+        drop(bans);
+
+        warn!(
+            "Banned {} for {} after {} failed logins within {}",
+            ip,
+            format_duration(ban_duration),
+            max_failures,
+            format_duration(window)
+        );
+        self.changed.send_replace(());
+    }
+
+    /// Ban `ip` until the server stops
+    pub fn ban(&self, ip: IpAddr, reason: &str) {
+        self.bans.lock().unwrap().bans.insert(ip, Ban {
+            reason: reason.to_string(),
+            until: None,
+        });
+        info!("Banned {} until the server stops", ip);
+        self.changed.send_replace(());
+    }
+
+    /// Lift a ban, returning `false` if the address wasn't banned
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+        bans.failures.remove(&ip);
+        let removed = bans.bans.remove(&ip).is_some();
+        drop(bans);
+
+        if removed {
+            info!("Lifted ban of {}", ip);
+            self.changed.send_replace(());
+        }
+        removed
+    }
+
+    /// Forget expired bans and failures older than the window
+    pub fn expire(&self) {
+        self.expire_at(Instant::now());
+    }
+
+    fn expire_at(&self, now: Instant) {
+        let window = self.protection.window;
+        let mut bans = self.bans.lock().unwrap();
+        let before = bans.bans.len();
+        bans.bans.retain(|ip, ban| {
+            let active = ban.is_active(now);
+            if !active {
+                info!("Ban of {} expired", ip);
+            }
+            active
+        });
+        bans.failures.retain(|_, failures| failures.last().is_some_and(|at| now.duration_since(*at) < window));
+        let expired = bans.bans.len() != before;
+        drop(bans);
+
+        if expired {
+            self.changed.send_replace(());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bans.lock().unwrap().bans.is_empty()
+    }
+
+    pub fn snapshot(&self) -> Vec<BanSnapshot> {
+        let now = Instant::now();
+        let bans = self.bans.lock().unwrap();
+        let mut snapshot: Vec<BanSnapshot> = bans
+            .bans
+            .iter()
+            .filter(|(_, ban)| ban.is_active(now))
+            .map(|(ip, ban)| BanSnapshot {
+                address: *ip,
+                reason: ban.reason.clone(),
+                remaining: ban.until.map(|until| until - now),
+            })
+            .collect();
+        snapshot.sort_by_key(|ban| ban.address);
+        snapshot
+    }
+
+    pub fn clear(&self) {
+        let mut bans = self.bans.lock().unwrap();
+        bans.bans.clear();
+        bans.failures.clear();
+    }
+}
+
+/// Point-in-time view of a ban for display
+#[derive(Debug, Clone)]
+pub struct BanSnapshot {
+    pub address: IpAddr,
+    pub reason: String,
+    /// `None` for bans that last until the server stops
+    pub remaining: Option<Duration>,
+}
+
+impl BanSnapshot {
+    /// One-line description, e.g. `203.0.113.7 (5 failed logins, 14m 20s left)`
+    pub fn summary(&self) -> String {
+        match self.remaining {
+            Some(remaining) => format!("{} ({}, {} left)", self.address, self.reason, format_duration(remaining)),
+            None => format!("{} ({}, until the server stops)", self.address, self.reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+
+    fn ban_list() -> BanList {
+        let protection = LoginProtection {
+            max_failures: 3,
+            window: Duration::from_secs(60),
+            ban_duration: Duration::from_secs(300),
+        };
+        BanList::new(protection, Arc::new(watch::channel(()).0))
+    }
+
+    #[test]
+    fn bans_at_exactly_the_threshold() {
+        let bans = ban_list();
+        let start = Instant::now();
+        bans.record_failure_at(CLIENT, start);
+        bans.record_failure_at(CLIENT, start + Duration::from_secs(1));
+        assert!(!bans.is_banned_at(CLIENT, start + Duration::from_secs(1)));
+
+        bans.record_failure_at(CLIENT, start + Duration::from_secs(2));
+        assert!(bans.is_banned_at(CLIENT, start + Duration::from_secs(2)));
+        assert!(!bans.is_banned_at(IpAddr::V4(Ipv4Addr::LOCALHOST), start + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn failures_outside_the_window_dont_count() {
+        let bans = ban_list();
+        let start = Instant::now();
+        bans.record_failure_at(CLIENT, start);
+        bans.record_failure_at(CLIENT, start + Duration::from_secs(30));
+        // The first failure is exactly one window old by now
+        bans.record_failure_at(CLIENT, start + Duration::from_secs(60));
+        assert!(!bans.is_banned_at(CLIENT, start + Duration::from_secs(60)));
+
+        bans.record_failure_at(CLIENT, start + Duration::from_secs(61));
+        assert!(bans.is_banned_at(CLIENT, start + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn ban_expires_after_the_ban_duration() {
+        let bans = ban_list();
+        let start = Instant::now();
+        for _ in 0..3 {
+            bans.record_failure_at(CLIENT, start);
+        }
+        assert!(bans.is_banned_at(CLIENT, start + Duration::from_secs(299)));
+        assert!(!bans.is_banned_at(CLIENT, start + Duration::from_secs(300)));
+
+        bans.expire_at(start + Duration::from_secs(299));
+        assert!(!bans.is_empty());
+        bans.expire_at(start + Duration::from_secs(300));
+        assert!(bans.is_empty());
+
+        // Failures before the ban don't carry over
+        bans.record_failure_at(CLIENT, start + Duration::from_secs(301));
+        assert!(!bans.is_banned_at(CLIENT, start + Duration::from_secs(301)));
+    }
+}
@@ -0,0 +1,254 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use crate::logging::LogRotation;
+use crate::port_mapping::PortMappingMethod;
+
+/// Server settings that can be stored in the config file. Every field is optional;
+/// unset fields fall back to the profile, then the top-level section, then the built-in defaults.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub directory: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pasv_range: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bind: Option<String>,
+    /// IPv4 address, DNS name or "auto", see `PassiveHostConfig::parse`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passive_host: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passive_host_echo: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port_mapping: Option<PortMappingMethod>,
+    /// IPv4 address or UPnP description URL, see `PortMappingConfig::parse`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port_mapping_gateway: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shutdown_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub users_file: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_only: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drop_box: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub anonymous: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub anonymous_read_only: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub anonymous_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_cert: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_key: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_self_signed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_required: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_implicit_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audit_log: Option<PathBuf>,
+    /// Rates such as "5MiB/s", see `throttle::parse_rate`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_download_rate: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_upload_rate: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_user_download_rate: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_user_upload_rate: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_connections_per_ip: Option<usize>,
+    /// CIDR ranges such as "192.168.0.0/16" allowed to connect
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deny: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lan_only: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ban_after: Option<u32>,
+    /// In seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ban_window: Option<u64>,
+    /// In seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ban_duration: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_file: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_rotation: Option<LogRotation>,
+    /// In MiB
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_max_size: Option<u64>,
+}
+
+macro_rules! merge_fields {
+    ($target:ident, $other:ident, $($field:ident),+ $(,)?) => {
+        $(
+            if $other.$field.is_some() {
+                $target.$field = $other.$field.clone();
+            }
+        )+
+    };
+}
+
+impl Settings {
+    /// Override these settings with every field set in `other`
+    pub fn merge(&mut self, other: &Settings) {
+        merge_fields!(
+            self, other,
+            directory, username, password, port, pasv_range, bind, passive_host, passive_host_echo,
+            port_mapping, port_mapping_gateway, shutdown_timeout, users_file,
+            read_only, drop_box, anonymous, anonymous_read_only, anonymous_dir,
+            tls_cert, tls_key, tls_self_signed, tls_required, tls_implicit_port, audit_log,
+            max_download_rate, max_upload_rate, max_user_download_rate, max_user_upload_rate,
+            max_connections, max_connections_per_ip, ban_after, ban_window, ban_duration, allow, deny, lan_only,
+            log_file, log_rotation, log_max_size,
+        );
+    }
+}
+
+/// Contents of `sixftp.toml`:
+///
+/// ```toml
+/// default_profile = "lan-share"
+/// port = 2121
+///
+/// [profiles.lan-share]
+/// directory = "/srv/share"
+/// read_only = true
+///
+/// [profiles.public-dropbox]
+/// directory = "/srv/inbox"
+/// drop_box = true
+/// tls_self_signed = true
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConfigFile {
+    /// Profile used when none is selected explicitly
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_profile: Option<String>,
+    /// Settings shared by all profiles
+    #[serde(flatten)]
+    pub settings: Settings,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Settings>,
+}
+
+impl ConfigFile {
+    /// Load the config file, returning an empty config if it doesn't exist
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file '{}'", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("Failed to parse config file '{}'", path.display()))
+    }
+
+    /// Settings for the given profile (or the default profile), layered over the shared settings
+    pub fn resolve(&self, profile: Option<&str>) -> Result<Settings> {
+        let mut settings = self.settings.clone();
+
+        if let Some(name) = profile.or(self.default_profile.as_deref()) {
+            let profile = self.profiles.get(name).ok_or_else(|| {
+                let available: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+                anyhow::anyhow!(
+                    "Unknown profile '{}' (available: {})",
+                    name,
+                    if available.is_empty() { "none".to_string() } else { available.join(", ") }
+                )
+            })?;
+            settings.merge(profile);
+        }
+
+        Ok(settings)
+    }
+
+    pub fn profile_names(&self) -> Vec<String> {
+        self.profiles.keys().cloned().collect()
+    }
+}
+
+/// Last-used GUI settings, restored on the next launch
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GuiState {
+    /// Profile that was selected in the GUI
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(flatten)]
+    pub settings: Settings,
+}
+
+impl GuiState {
+    /// Load the saved state, `None` if nothing has been saved yet
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read GUI settings '{}'", path.display()))?;
+        let state = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse GUI settings '{}'", path.display()))?;
+        Ok(Some(state))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create '{}'", dir.display()))?;
+        }
+
+        // May contain the FTP password, so keep it private
+        write_private(path, &toml::to_string_pretty(self)?)
+    }
+}
+
+/// Write a file readable only by the current user where the platform supports it
+pub fn write_private(path: &Path, contents: &str) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("Failed to write '{}'", path.display()))?;
+        file.write_all(contents.as_bytes())?;
+    }
+
+    #[cfg(not(unix))]
+    std::fs::write(path, contents)
+        .with_context(|| format!("Failed to write '{}'", path.display()))?;
+
+    Ok(())
+}
+
+/// Where the GUI keeps its last-used settings
+pub fn gui_state_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("sixftp").join("gui-state.toml"))
+}
+
+/// Default location of the config file, e.g. `~/.config/sixftp/sixftp.toml` on Linux
+pub fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("sixftp").join("sixftp.toml"))
+}
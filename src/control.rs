@@ -0,0 +1,60 @@
+use tokio::io::{AsyncBufReadExt, BufReader};
+use crate::server;
+use crate::sessions::SessionRegistry;
+
+const HELP: &str = "Commands:
+   clients        List logged-in clients
+   kick <id>      Disconnect a client
+   ban <id>       Disconnect a client and refuse its address until the server stops
+   bans           List banned addresses
+   unban <ip>     Lift the ban of an address
+   stop           Stop the server after active transfers finish
+   help           Show this help";
+
+/// Read control commands from stdin in CLI mode. Returns when `stop` is entered; if stdin is closed
+/// (e.g. when running as a service) it never returns, so the server keeps running.
+pub async fn run(sessions: SessionRegistry) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (None, _) => {}
+            (Some("clients" | "list"), _) => {
+                let clients = sessions.snapshot();
+                if clients.is_empty() {
+                    println!("No clients logged in");
+                }
+                for client in clients {
+                    println!("   [{}] {}", client.id, client.summary());
+                }
+            }
+            (Some(command @ ("kick" | "ban")), Some(id)) => match id.parse() {
+                Ok(id) if sessions.kick(id, command == "ban") => {}
+                Ok(id) => println!("No client with id {}, see 'clients'", id),
+                Err(_) => println!("Invalid client id '{}'", id),
+            },
+            (Some(command @ ("kick" | "ban")), None) => println!("Usage: {} <id>", command),
+            (Some("bans"), _) => {
+                let bans = sessions.bans().snapshot();
+                if bans.is_empty() {
+                    println!("No addresses banned");
+                }
+                for ban in bans {
+                    println!("   {}", ban.summary());
+                }
+            }
+            (Some("unban"), Some(ip)) => match server::parse_bind_address(ip) {
+                Ok(ip) if sessions.bans().unban(ip) => {}
+                Ok(ip) => println!("{} is not banned, see 'bans'", ip),
+                Err(_) => println!("Invalid address '{}'", ip),
+            },
+            (Some("unban"), None) => println!("Usage: unban <ip>"),
+            (Some("stop" | "quit"), _) => return,
+            (Some("help"), _) => println!("{}", HELP),
+            (Some(command), _) => println!("Unknown command '{}', type 'help' for a list", command),
+        }
+    }
+
+    std::future::pending::<()>().await;
+}
@@ -1,14 +1,27 @@
 use iced::{Element, Length, Task, Subscription, Event};
-use iced::widget::{button, column, container, row, text, text_input, scrollable, text_editor, Space};
+use iced::widget::{button, checkbox, column, container, pick_list, row, text, text_input, scrollable, text_editor, Column, Space};
 use iced::window;
-use std::sync::{Arc, Mutex};
 use tokio::runtime::Runtime;
 use anyhow::Result;
-use std::path::PathBuf;
+use std::collections::VecDeque;
 use std::net::IpAddr;
-use unftp_sbe_fs::ServerExt;
-use crate::network_info::ServerInfo;
-use tracing::info;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::auth::AnonymousAccess;
+use crate::bans::{BanSnapshot, LoginProtection};
+use crate::config::{self, ConfigFile, GuiState, Settings};
+use crate::ip_rules::IpRules;
+use crate::logging::{self, LogEntry};
+use crate::passive_host::{self, PassiveHostConfig};
+use crate::port_mapping::{PortMappingConfig, PortMappingMethod};
+use crate::server;
+use crate::sessions::{ConnectionLimits, SessionSnapshot};
+use crate::storage::AccessMode;
+use crate::throttle::{self, RateLimits};
+use crate::tls::TlsConfig;
+use crate::users::{UserAccount, UsersFile};
+use tracing::{error, info, Level};
 
 #[derive(Debug, Clone)]
 pub enum Message {
@@ -18,8 +31,56 @@ pub enum Message {
     PortChanged(String),
     PasvRangeChanged(String),
     BindAddressChanged(String),
+    ShutdownTimeoutChanged(String),
+    PassiveHostChanged(String),
+    PassiveHostEchoChanged(String),
+    PortMappingSelected(PortMappingMethod),
+    PortMappingGatewayChanged(String),
+    LanOnlyToggled(bool),
+    AllowChanged(String),
+    DenyChanged(String),
+    BanAfterChanged(String),
+    BanWindowChanged(String),
+    BanDurationChanged(String),
+    UsersFileChanged(String),
+    LoadUsersFile,
+    SaveUsersFile,
+    NewUserNameChanged(String),
+    NewUserPasswordChanged(String),
+    NewUserHomeChanged(String),
+    NewUserReadToggled(bool),
+    NewUserWriteToggled(bool),
+    AddUser,
+    RemoveUser(usize),
+    TlsCertChanged(String),
+    TlsKeyChanged(String),
+    TlsRequiredToggled(bool),
+    TlsSelfSignedToggled(bool),
+    TlsImplicitPortChanged(String),
+    ReadOnlyToggled(bool),
+    DropBoxToggled(bool),
+    AnonymousToggled(bool),
+    AnonymousReadOnlyToggled(bool),
+    AnonymousDirChanged(String),
+    AuditLogChanged(String),
+    MaxConnectionsChanged(String),
+    MaxConnectionsPerIpChanged(String),
+    MaxDownloadRateChanged(String),
+    MaxUploadRateChanged(String),
+    MaxUserDownloadRateChanged(String),
+    MaxUserUploadRateChanged(String),
+    ProfileSelected(String),
     StartServer,
     StopServer,
+    ServerStarted(Result<StartedServer, String>),
+    ServerStopped,
+    SessionsUpdated(Vec<SessionSnapshot>, Vec<BanSnapshot>),
+    OpenLogFile,
+    LogReceived(Vec<LogEntry>),
+    LogLevelSelected(Level),
+    KickSession(u64),
+    BanSession(u64),
+    Unban(IpAddr),
     ServerInfoEdited(text_editor::Action),
     EventOccurred(Event),
 }
@@ -31,58 +92,545 @@ pub struct SixFtpGui {
     port: String,
     pasv_range: String,
     bind_address: String,
+    shutdown_timeout: String,
+    /// IPv4 address, DNS name or "auto", see `PassiveHostConfig`
+    passive_host: String,
+    passive_host_echo: String,
+    port_mapping: PortMappingMethod,
+    /// IPv4 address or UPnP description URL, see `PortMappingConfig`
+    port_mapping_gateway: String,
+    /// Comma-separated CIDR ranges, see `IpRules`
+    allow: String,
+    deny: String,
+    lan_only: bool,
+    /// Failed login protection, see `LoginProtection`
+    ban_after: String,
+    ban_window: String,
+    ban_duration: String,
+    users_file: String,
+    accounts: Vec<UserAccount>,
+    new_user_name: String,
+    new_user_password: String,
+    new_user_home: String,
+    new_user_read: bool,
+    new_user_write: bool,
+    tls_cert: String,
+    tls_key: String,
+    tls_required: bool,
+    tls_self_signed: bool,
+    tls_implicit_port: String,
+    read_only: bool,
+    drop_box: bool,
+    anonymous: bool,
+    anonymous_read_only: bool,
+    anonymous_dir: String,
+    audit_log: String,
+    /// Concurrent client limits, empty for unlimited
+    max_connections: String,
+    max_connections_per_ip: String,
+    /// Bandwidth limits such as "5MiB/s", empty for unlimited
+    max_download_rate: String,
+    max_upload_rate: String,
+    max_user_download_rate: String,
+    max_user_upload_rate: String,
+    config_file: ConfigFile,
+    selected_profile: Option<String>,
     server_running: bool,
+    /// Start stays disabled while the server binds its ports
+    server_starting: bool,
+    /// Stop was requested and active transfers are draining
+    server_stopping: bool,
+    /// Close the window once the server has stopped
+    close_when_stopped: bool,
     server_status: String,
     server_info: text_editor::Content,
     server_status_content: text_editor::Content,
-    server_handle: Option<Arc<Mutex<ServerHandle>>>,
+    server_handle: Option<ServerHandle>,
+    /// Logged-in clients of the running server
+    sessions: Vec<SessionSnapshot>,
+    /// Addresses the running server refuses
+    bans: Vec<BanSnapshot>,
+    /// Recent log lines for the log pane
+    log_entries: VecDeque<LogEntry>,
+    /// Most verbose level shown in the log pane
+    log_level: Level,
 }
 
 impl Default for SixFtpGui {
     fn default() -> Self {
         let server_status = "Server not started".to_string();
-        Self {
+        let mut gui = Self {
             directory: ".".to_string(),
             username: "user".to_string(),
             password: "password".to_string(),
             port: "9000".to_string(),
             pasv_range: "30000-30100".to_string(),
             bind_address: "0.0.0.0".to_string(),
+            shutdown_timeout: "30".to_string(),
+            passive_host: String::new(),
+            passive_host_echo: String::new(),
+            port_mapping: PortMappingMethod::Off,
+            port_mapping_gateway: String::new(),
+            allow: String::new(),
+            deny: String::new(),
+            lan_only: false,
+            ban_after: "5".to_string(),
+            ban_window: "600".to_string(),
+            ban_duration: "900".to_string(),
+            users_file: String::new(),
+            accounts: Vec::new(),
+            new_user_name: String::new(),
+            new_user_password: String::new(),
+            new_user_home: String::new(),
+            new_user_read: true,
+            new_user_write: true,
+            tls_cert: String::new(),
+            tls_key: String::new(),
+            tls_required: false,
+            tls_self_signed: false,
+            tls_implicit_port: String::new(),
+            read_only: false,
+            drop_box: false,
+            anonymous: false,
+            anonymous_read_only: true,
+            anonymous_dir: String::new(),
+            audit_log: String::new(),
+            max_connections: String::new(),
+            max_connections_per_ip: String::new(),
+            max_download_rate: String::new(),
+            max_upload_rate: String::new(),
+            max_user_download_rate: String::new(),
+            max_user_upload_rate: String::new(),
+            config_file: ConfigFile::default(),
+            selected_profile: None,
             server_running: false,
+            server_starting: false,
+            server_stopping: false,
+            close_when_stopped: false,
             server_status: server_status.clone(),
             server_info: text_editor::Content::new(),
             server_status_content: text_editor::Content::with_text(&server_status),
             server_handle: None,
+            sessions: Vec::new(),
+            bans: Vec::new(),
+            log_entries: VecDeque::new(),
+            log_level: Level::INFO,
+        };
+
+        // Start from the config file's default profile, if there is one
+        if let Some(config_path) = config::default_config_path() {
+            match ConfigFile::load(&config_path).and_then(|c| c.resolve(None).map(|s| (c, s))) {
+                Ok((config_file, settings)) => {
+                    gui.selected_profile = config_file.default_profile.clone();
+                    gui.config_file = config_file;
+                    gui.apply_settings(&settings);
+                }
+                Err(e) => gui.set_status(format!("{:#}", e)),
+            }
         }
+
+        // Settings from the last session take precedence over the config file
+        if let Some(state_path) = config::gui_state_path() {
+            match GuiState::load(&state_path) {
+                Ok(Some(state)) => {
+                    gui.reset_options();
+                    gui.apply_settings(&state.settings);
+                    gui.selected_profile = state.profile.filter(|p| gui.config_file.profiles.contains_key(p));
+                }
+                Ok(None) => {}
+                Err(e) => gui.set_status(format!("{:#}", e)),
+            }
+        }
+
+        gui
     }
 }
 
 struct ServerHandle {
-    _runtime: Runtime,
-    server_tasks: Vec<tokio::task::JoinHandle<()>>,
+    runtime: Runtime,
+    server: server::ServerHandle,
+}
+
+/// A started server on its way from the startup task to the GUI. Messages have to be `Clone`, so the
+/// handle travels in a shared slot that the GUI takes it out of once.
+#[derive(Clone)]
+pub struct StartedServer(Arc<Mutex<Option<ServerHandle>>>);
+
+impl StartedServer {
+    fn take(&self) -> Option<ServerHandle> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+impl std::fmt::Debug for StartedServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StartedServer")
+    }
 }
 
 impl ServerHandle {
-    fn shutdown(self) {
-        // Abort all server tasks
-        for task in &self.server_tasks {
-            task.abort();
-        }
+    /// Drain the server on its own runtime without blocking the GUI, then release the runtime
+    fn shutdown(self) -> Task<Message> {
+        let ServerHandle { runtime, server } = self;
+        let stopped = runtime.spawn(server.shutdown());
 
-        // Intentionally leak the runtime to avoid drop panics
-        // This is acceptable for a GUI application where the server stop
-        // is typically followed by the application closing anyway
-        std::mem::forget(self);
+        Task::perform(
+            async move {
+                let _ = stopped.await;
+                // Dropping a runtime from within another one panics, so release it in the background
+                runtime.shutdown_background();
+            },
+            |_| Message::ServerStopped,
+        )
     }
 }
 
 impl SixFtpGui {
     fn subscription(&self) -> Subscription<Message> {
-        iced::event::listen().map(Message::EventOccurred)
+        let mut subscriptions = vec![iced::event::listen().map(Message::EventOccurred)];
+
+        // Forward new log lines, remembering the last one sent so none are repeated
+        if let Some(buffer) = logging::gui_log() {
+            let mut changes = buffer.subscribe();
+            changes.mark_changed();
+            let log = iced::futures::stream::unfold((changes, 0), move |(mut changes, mut last)| async move {
+                loop {
+                    changes.changed().await.ok()?;
+                    let entries = buffer.since(last);
+                    if let Some(entry) = entries.last() {
+                        last = entry.sequence;
+                        return Some((Message::LogReceived(entries), (changes, last)));
+                    }
+                }
+            });
+            subscriptions.push(Subscription::run_with_id("log", log));
+        }
+
+        // Re-read the session list whenever the server reports a change
+        if let Some(handle) = &self.server_handle {
+            let registry = handle.server.sessions().clone();
+            let mut changes = registry.subscribe();
+            changes.mark_changed();
+            let sessions = iced::futures::stream::unfold((registry, changes), |(registry, mut changes)| async move {
+                changes.changed().await.ok()?;
+                let message = Message::SessionsUpdated(registry.snapshot(), registry.bans().snapshot());
+                Some((message, (registry, changes)))
+            });
+            subscriptions.push(Subscription::run_with_id("sessions", sessions));
+        }
+
+        Subscription::batch(subscriptions)
+    }
+
+    fn set_status(&mut self, status: String) {
+        self.server_status_content = text_editor::Content::with_text(&status);
+        self.server_status = status;
+    }
+
+    /// Copy every setting present in `settings` into the input fields
+    fn apply_settings(&mut self, settings: &Settings) {
+        let path_string = |path: &PathBuf| path.display().to_string();
+
+        if let Some(directory) = &settings.directory {
+            self.directory = path_string(directory);
+        }
+        if let Some(username) = &settings.username {
+            self.username = username.clone();
+        }
+        if let Some(password) = &settings.password {
+            self.password = password.clone();
+        }
+        if let Some(port) = settings.port {
+            self.port = port.to_string();
+        }
+        if let Some(pasv_range) = &settings.pasv_range {
+            self.pasv_range = pasv_range.clone();
+        }
+        if let Some(bind) = &settings.bind {
+            self.bind_address = bind.clone();
+        }
+        if let Some(shutdown_timeout) = settings.shutdown_timeout {
+            self.shutdown_timeout = shutdown_timeout.to_string();
+        }
+        if let Some(passive_host) = &settings.passive_host {
+            self.passive_host = passive_host.clone();
+        }
+        if let Some(passive_host_echo) = &settings.passive_host_echo {
+            self.passive_host_echo = passive_host_echo.clone();
+        }
+        if let Some(port_mapping) = settings.port_mapping {
+            self.port_mapping = port_mapping;
+        }
+        if let Some(gateway) = &settings.port_mapping_gateway {
+            self.port_mapping_gateway = gateway.clone();
+        }
+        if let Some(lan_only) = settings.lan_only {
+            self.lan_only = lan_only;
+        }
+        if let Some(allow) = &settings.allow {
+            self.allow = allow.join(", ");
+        }
+        if let Some(deny) = &settings.deny {
+            self.deny = deny.join(", ");
+        }
+        if let Some(ban_after) = settings.ban_after {
+            self.ban_after = ban_after.to_string();
+        }
+        if let Some(ban_window) = settings.ban_window {
+            self.ban_window = ban_window.to_string();
+        }
+        if let Some(ban_duration) = settings.ban_duration {
+            self.ban_duration = ban_duration.to_string();
+        }
+        if let Some(users_file) = &settings.users_file {
+            self.users_file = path_string(users_file);
+            self.load_users_file();
+        }
+        if let Some(read_only) = settings.read_only {
+            self.read_only = read_only;
+        }
+        if let Some(drop_box) = settings.drop_box {
+            self.drop_box = drop_box;
+        }
+        if let Some(anonymous) = settings.anonymous {
+            self.anonymous = anonymous;
+        }
+        if let Some(anonymous_read_only) = settings.anonymous_read_only {
+            self.anonymous_read_only = anonymous_read_only;
+        }
+        if let Some(anonymous_dir) = &settings.anonymous_dir {
+            self.anonymous_dir = path_string(anonymous_dir);
+        }
+        if let Some(tls_cert) = &settings.tls_cert {
+            self.tls_cert = path_string(tls_cert);
+        }
+        if let Some(tls_key) = &settings.tls_key {
+            self.tls_key = path_string(tls_key);
+        }
+        if let Some(tls_self_signed) = settings.tls_self_signed {
+            self.tls_self_signed = tls_self_signed;
+        }
+        if let Some(tls_required) = settings.tls_required {
+            self.tls_required = tls_required;
+        }
+        if let Some(port) = settings.tls_implicit_port {
+            self.tls_implicit_port = port.to_string();
+        }
+        if let Some(audit_log) = &settings.audit_log {
+            self.audit_log = path_string(audit_log);
+        }
+        if let Some(max) = settings.max_connections {
+            self.max_connections = max.to_string();
+        }
+        if let Some(max) = settings.max_connections_per_ip {
+            self.max_connections_per_ip = max.to_string();
+        }
+        if let Some(rate) = &settings.max_download_rate {
+            self.max_download_rate = rate.clone();
+        }
+        if let Some(rate) = &settings.max_upload_rate {
+            self.max_upload_rate = rate.clone();
+        }
+        if let Some(rate) = &settings.max_user_download_rate {
+            self.max_user_download_rate = rate.clone();
+        }
+        if let Some(rate) = &settings.max_user_upload_rate {
+            self.max_user_upload_rate = rate.clone();
+        }
+    }
+
+    /// Snapshot of the current input fields
+    fn current_settings(&self) -> Settings {
+        let optional_path = |value: &str| match value.trim() {
+            "" => None,
+            path => Some(PathBuf::from(path)),
+        };
+        let optional_string = |value: &str| match value.trim() {
+            "" => None,
+            value => Some(value.to_string()),
+        };
+        let optional_list = |value: &str| {
+            let items: Vec<String> = split_list(value).map(str::to_string).collect();
+            (!items.is_empty()).then_some(items)
+        };
+
+        Settings {
+            directory: Some(PathBuf::from(&self.directory)),
+            username: Some(self.username.clone()),
+            password: Some(self.password.clone()),
+            port: self.port.parse().ok(),
+            pasv_range: Some(self.pasv_range.clone()),
+            bind: Some(self.bind_address.clone()),
+            shutdown_timeout: self.shutdown_timeout.parse().ok(),
+            passive_host: optional_string(&self.passive_host),
+            passive_host_echo: optional_string(&self.passive_host_echo),
+            port_mapping: Some(self.port_mapping),
+            port_mapping_gateway: optional_string(&self.port_mapping_gateway),
+            lan_only: Some(self.lan_only),
+            allow: optional_list(&self.allow),
+            deny: optional_list(&self.deny),
+            ban_after: self.ban_after.trim().parse().ok(),
+            ban_window: self.ban_window.trim().parse().ok(),
+            ban_duration: self.ban_duration.trim().parse().ok(),
+            users_file: optional_path(&self.users_file),
+            read_only: Some(self.read_only),
+            drop_box: Some(self.drop_box),
+            anonymous: Some(self.anonymous),
+            anonymous_read_only: Some(self.anonymous_read_only),
+            anonymous_dir: optional_path(&self.anonymous_dir),
+            tls_cert: optional_path(&self.tls_cert),
+            tls_key: optional_path(&self.tls_key),
+            tls_self_signed: Some(self.tls_self_signed),
+            tls_required: Some(self.tls_required),
+            tls_implicit_port: self.tls_implicit_port.trim().parse().ok(),
+            audit_log: optional_path(&self.audit_log),
+            max_connections: self.max_connections.trim().parse().ok(),
+            max_connections_per_ip: self.max_connections_per_ip.trim().parse().ok(),
+            max_download_rate: optional_string(&self.max_download_rate),
+            max_upload_rate: optional_string(&self.max_upload_rate),
+            max_user_download_rate: optional_string(&self.max_user_download_rate),
+            max_user_upload_rate: optional_string(&self.max_user_upload_rate),
+            // The GUI always logs to its own file, see `LogFileConfig::gui_default`
+            ..Settings::default()
+        }
+    }
+
+    /// Remember the current settings for the next launch
+    fn save_gui_state(&self) {
+        let Some(state_path) = config::gui_state_path() else {
+            return;
+        };
+
+        let state = GuiState {
+            profile: self.selected_profile.clone(),
+            settings: self.current_settings(),
+        };
+        match state.save(&state_path) {
+            Ok(()) => info!("GUI: Saved settings to {}", state_path.display()),
+            Err(e) => error!("GUI: Failed to save settings: {:#}", e),
+        }
+    }
+
+    /// Turn off optional features so one profile's options don't carry over into the next
+    fn reset_options(&mut self) {
+        self.users_file.clear();
+        self.accounts.clear();
+        self.read_only = false;
+        self.drop_box = false;
+        self.anonymous = false;
+        self.anonymous_read_only = true;
+        self.anonymous_dir.clear();
+        self.tls_cert.clear();
+        self.tls_key.clear();
+        self.tls_self_signed = false;
+        self.tls_required = false;
+        self.tls_implicit_port.clear();
+        self.audit_log.clear();
+        self.passive_host.clear();
+        self.passive_host_echo.clear();
+        self.port_mapping = PortMappingMethod::Off;
+        self.port_mapping_gateway.clear();
+        self.lan_only = false;
+        self.allow.clear();
+        self.deny.clear();
+        self.max_connections.clear();
+        self.max_connections_per_ip.clear();
+        self.max_download_rate.clear();
+        self.max_upload_rate.clear();
+        self.max_user_download_rate.clear();
+        self.max_user_upload_rate.clear();
+    }
+
+    fn connection_limits(&self) -> Result<ConnectionLimits> {
+        let parse = |value: &str, name: &str| match value.trim() {
+            "" => Ok(None),
+            value => value
+                .parse()
+                .map(Some)
+                .map_err(|_| anyhow::anyhow!("Invalid {} '{}'", name, value)),
+        };
+        Ok(ConnectionLimits {
+            max: parse(&self.max_connections, "maximum connections")?,
+            per_ip: parse(&self.max_connections_per_ip, "maximum connections per IP")?,
+        })
+    }
+
+    fn rate_limits(&self) -> Result<RateLimits> {
+        Ok(RateLimits {
+            download: throttle::parse_rate(&self.max_download_rate)?,
+            upload: throttle::parse_rate(&self.max_upload_rate)?,
+            user_download: throttle::parse_rate(&self.max_user_download_rate)?,
+            user_upload: throttle::parse_rate(&self.max_user_upload_rate)?,
+        })
+    }
+
+    fn select_profile(&mut self, name: String) {
+        match self.config_file.resolve(Some(&name)) {
+            Ok(settings) => {
+                self.reset_options();
+                self.apply_settings(&settings);
+                self.set_status(format!("Loaded profile '{}'", name));
+                self.selected_profile = Some(name);
+            }
+            Err(e) => self.set_status(format!("{:#}", e)),
+        }
+    }
+
+    fn load_users_file(&mut self) {
+        match UsersFile::load(std::path::Path::new(self.users_file.trim())) {
+            Ok(users_file) => {
+                self.set_status(format!("Loaded {} account(s) from {}", users_file.users.len(), self.users_file.trim()));
+                self.accounts = users_file.users;
+            }
+            Err(e) => self.set_status(format!("{:#}", e)),
+        }
+    }
+
+    fn save_users_file(&mut self) {
+        if self.users_file.trim().is_empty() {
+            self.set_status("Enter a users file path to save to".to_string());
+            return;
+        }
+
+        let users_file = UsersFile {
+            users: self.accounts.clone(),
+        };
+        match users_file.save(std::path::Path::new(self.users_file.trim())) {
+            Ok(()) => self.set_status(format!("Saved {} account(s) to {}", self.accounts.len(), self.users_file.trim())),
+            Err(e) => self.set_status(format!("{:#}", e)),
+        }
+    }
+
+    fn add_user(&mut self) {
+        let username = self.new_user_name.trim();
+        if username.is_empty() || self.new_user_password.is_empty() {
+            self.set_status("New users need a username and password".to_string());
+            return;
+        }
+        if self.accounts.iter().any(|a| a.username == username) {
+            self.set_status(format!("User '{}' already exists", username));
+            return;
+        }
+
+        let home = match self.new_user_home.trim() {
+            "" => None,
+            home => Some(PathBuf::from(home.trim_start_matches('/'))),
+        };
+
+        match UserAccount::new(username, &self.new_user_password, home, self.new_user_read, self.new_user_write) {
+            Ok(account) => {
+                self.accounts.push(account);
+                self.new_user_name.clear();
+                self.new_user_password.clear();
+                self.new_user_home.clear();
+                self.set_status("User added, save the users file to keep it".to_string());
+            }
+            Err(e) => self.set_status(format!("{:#}", e)),
+        }
     }
 
     fn start_server(&mut self) -> Task<Message> {
-        if self.server_running {
+        if self.server_running || self.server_starting || self.server_stopping {
             return Task::none();
         }
 
@@ -90,143 +638,245 @@ impl SixFtpGui {
         let port = match self.port.parse::<u16>() {
             Ok(p) => p,
             Err(_) => {
-                self.server_status = "Invalid port number".to_string();
+                self.set_status("Invalid port number".to_string());
                 return Task::none();
             }
         };
 
-        let pasv_range = match parse_pasv_range(&self.pasv_range) {
+        let pasv_range = match server::parse_pasv_range(&self.pasv_range, port) {
             Ok(range) => range,
             Err(e) => {
-                self.server_status = format!("Invalid passive range: {}", e);
+                self.set_status(format!("Invalid passive range: {}", e));
                 return Task::none();
             }
         };
 
-        // Strip brackets from IPv6 addresses if present
-        let bind_address_cleaned = self.bind_address.trim()
-            .trim_start_matches('[')
-            .trim_end_matches(']');
-
-        let bind_addr: IpAddr = match bind_address_cleaned.parse() {
-            Ok(addr) => addr,
+        let shutdown_timeout = match self.shutdown_timeout.trim().parse::<u64>() {
+            Ok(seconds) => Duration::from_secs(seconds),
             Err(_) => {
-                self.server_status = "Invalid bind address".to_string();
+                self.set_status("Invalid shutdown timeout".to_string());
                 return Task::none();
             }
         };
 
-        let directory = PathBuf::from(&self.directory);
-        if !directory.exists() {
-            self.server_status = "Directory does not exist".to_string();
-            return Task::none();
-        }
+        let allow: Vec<String> = split_list(&self.allow).map(str::to_string).collect();
+        let deny: Vec<String> = split_list(&self.deny).map(str::to_string).collect();
+        let ip_rules = match IpRules::parse(&allow, &deny, self.lan_only) {
+            Ok(rules) => rules,
+            Err(e) => {
+                self.set_status(format!("{:#}", e));
+                return Task::none();
+            }
+        };
 
-        // Create a new runtime for the server
-        let runtime = Runtime::new().unwrap();
+        let login_protection = match (
+            self.ban_after.trim().parse::<u32>(),
+            self.ban_window.trim().parse::<u64>(),
+            self.ban_duration.trim().parse::<u64>(),
+        ) {
+            (Ok(max_failures), Ok(window), Ok(ban_duration)) => LoginProtection {
+                max_failures,
+                window: Duration::from_secs(window),
+                ban_duration: Duration::from_secs(ban_duration),
+            },
+            _ => {
+                self.set_status("Invalid failed login ban settings".to_string());
+                return Task::none();
+            }
+        };
 
-        // Clone values for the async tasks
-        let directory_clone = directory.clone();
-        let pasv_range_clone = pasv_range.clone();
+        let bind_addr = match server::parse_bind_address(&self.bind_address) {
+            Ok(addr) => addr,
+            Err(_) => {
+                self.set_status("Invalid bind address".to_string());
+                return Task::none();
+            }
+        };
 
-        let mut server_tasks = Vec::new();
+        let passive_host = match PassiveHostConfig::parse(&self.passive_host, Some(&self.passive_host_echo)) {
+            Ok(passive_host) => passive_host,
+            Err(e) => {
+                self.set_status(format!("{:#}", e));
+                return Task::none();
+            }
+        };
 
-        // If bind address is unspecified, bind to both IPv4 and IPv6
-        if bind_addr.is_unspecified() {
-            // IPv4 task
-            let directory_ipv4 = directory_clone.clone();
-            let pasv_range_ipv4 = pasv_range_clone.clone();
-            let ipv4_bind = "0.0.0.0".parse::<IpAddr>().unwrap();
+        let port_mapping = match PortMappingConfig::parse(self.port_mapping, Some(&self.port_mapping_gateway)) {
+            Ok(port_mapping) => port_mapping,
+            Err(e) => {
+                self.set_status(format!("{:#}", e));
+                return Task::none();
+            }
+        };
 
-            let ipv4_task = runtime.spawn(async move {
-                let bind_string = format!("{}:{}", ipv4_bind, port);
-                let server = libunftp::Server::with_fs(directory_ipv4)
-                    .passive_ports(pasv_range_ipv4)
-                    .passive_host(libunftp::options::PassiveHost::FromConnection)
-                    .greeting("Welcome to SixFTP Server")
-                    .build()
-                    .unwrap();
+        let connection_limits = match self.connection_limits() {
+            Ok(limits) => limits,
+            Err(e) => {
+                self.set_status(format!("{:#}", e));
+                return Task::none();
+            }
+        };
 
-                if let Err(e) = server.listen(bind_string).await {
-                    eprintln!("IPv4 server error: {}", e);
-                }
-            });
-            server_tasks.push(ipv4_task);
-
-            // IPv6 task
-            let directory_ipv6 = directory_clone.clone();
-            let pasv_range_ipv6 = pasv_range_clone.clone();
-            let ipv6_bind = "::".parse::<IpAddr>().unwrap();
-
-            let ipv6_task = runtime.spawn(async move {
-                let bind_string = format!("[{}]:{}", ipv6_bind, port);
-                let server = libunftp::Server::with_fs(directory_ipv6)
-                    .passive_ports(pasv_range_ipv6)
-                    .passive_host(libunftp::options::PassiveHost::FromConnection)
-                    .greeting("Welcome to SixFTP Server")
-                    .build()
-                    .unwrap();
-
-                if let Err(e) = server.listen(bind_string).await {
-                    eprintln!("IPv6 server error: {}", e);
-                }
-            });
-            server_tasks.push(ipv6_task);
-        } else {
-            // Use the specified bind address
-            let bind_string = if bind_addr.is_ipv6() {
-                format!("[{}]:{}", bind_addr, port)
-            } else {
-                format!("{}:{}", bind_addr, port)
-            };
+        let rate_limits = match self.rate_limits() {
+            Ok(limits) => limits,
+            Err(e) => {
+                self.set_status(format!("{:#}", e));
+                return Task::none();
+            }
+        };
 
-            let server_task = runtime.spawn(async move {
-                let server = libunftp::Server::with_fs(directory_clone)
-                    .passive_ports(pasv_range_clone)
-                    .passive_host(libunftp::options::PassiveHost::FromConnection)
-                    .greeting("Welcome to SixFTP Server")
-                    .build()
-                    .unwrap();
+        let implicit_port = match self.tls_implicit_port.trim() {
+            "" => None,
+            port => match port.parse::<u16>() {
+                Ok(port) => Some(port),
+                Err(_) => {
+                    self.set_status(format!("Invalid implicit FTPS port '{}'", port));
+                    return Task::none();
+                }
+            },
+        };
 
-                if let Err(e) = server.listen(bind_string).await {
-                    eprintln!("Server error: {}", e);
+        let tls = match (self.tls_cert.trim(), self.tls_key.trim()) {
+            _ if self.tls_self_signed => match TlsConfig::self_signed(self.tls_required) {
+                Ok(tls) => Some(tls),
+                Err(e) => {
+                    self.set_status(format!("{:#}", e));
+                    return Task::none();
                 }
-            });
-            server_tasks.push(server_task);
+            },
+            ("", "") if self.tls_required => {
+                self.set_status("Requiring TLS needs a certificate and key or a self-signed certificate".to_string());
+                return Task::none();
+            }
+            ("", "") if implicit_port.is_some() => {
+                self.set_status("Implicit FTPS needs a certificate and key or a self-signed certificate".to_string());
+                return Task::none();
+            }
+            ("", "") => None,
+            ("", _) | (_, "") => {
+                self.set_status("Both a TLS certificate and key are needed for FTPS".to_string());
+                return Task::none();
+            }
+            (cert, key) => match TlsConfig::new(PathBuf::from(cert), PathBuf::from(key), self.tls_required) {
+                Ok(tls) => Some(tls),
+                Err(e) => {
+                    self.set_status(format!("{:#}", e));
+                    return Task::none();
+                }
+            },
         }
+        .map(|tls| tls.with_implicit_port(implicit_port));
 
-        let handle = ServerHandle {
-            _runtime: runtime,
-            server_tasks,
+        let access_mode = if self.read_only {
+            AccessMode::ReadOnly
+        } else if self.drop_box {
+            AccessMode::DropBox
+        } else {
+            AccessMode::ReadWrite
         };
 
-        self.server_handle = Some(Arc::new(Mutex::new(handle)));
-        self.server_running = true;
-        self.server_status = "Server running".to_string();
-
-        // Generate comprehensive server information
-        let successful_bindings = if bind_addr.is_unspecified() {
-            vec![
-                "0.0.0.0".parse::<IpAddr>().unwrap(),
-                "::".parse::<IpAddr>().unwrap()
-            ]
+        let anonymous = if self.anonymous {
+            let anonymous_dir = match self.anonymous_dir.trim() {
+                "" => None,
+                dir => Some(PathBuf::from(dir.trim_start_matches('/'))),
+            };
+            match AnonymousAccess::new(self.anonymous_read_only, anonymous_dir) {
+                Ok(anonymous) => Some(anonymous),
+                Err(e) => {
+                    self.set_status(format!("{:#}", e));
+                    return Task::none();
+                }
+            }
         } else {
-            vec![bind_addr]
+            None
         };
 
-        info!("GUI: FTP server started successfully on port {} with {} binding(s)", port, successful_bindings.len());
-        
-        let server_info = ServerInfo {
-            successful_bindings,
+        let config = server::ServerConfig {
+            directory: PathBuf::from(&self.directory),
             port,
+            bind_addr,
             pasv_range,
-            directory,
+            passive_host,
+            port_mapping,
             username: self.username.clone(),
             password: self.password.clone(),
+            accounts: self.accounts.clone(),
+            anonymous,
+            access_mode,
+            tls,
+            shutdown_timeout,
+            audit_log: match self.audit_log.trim() {
+                "" => None,
+                path => Some(PathBuf::from(path)),
+            },
+            rate_limits,
+            connection_limits,
+            login_protection,
+            ip_rules,
+        };
+
+        // Create a new runtime for the server. The GUI already runs inside a Tokio runtime, so the
+        // start runs there and reports back with a message instead of blocking the update.
+        let runtime = match Runtime::new() {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                self.set_status(format!("Failed to create the server runtime: {}", e));
+                return Task::none();
+            }
         };
-        
+        let started = runtime.spawn(server::start(config));
+
+        self.server_starting = true;
+        self.set_status("Starting server...".to_string());
+
+        Task::perform(
+            async move {
+                match started.await {
+                    Ok(Ok(server)) => Ok(StartedServer(Arc::new(Mutex::new(Some(ServerHandle { runtime, server }))))),
+                    result => {
+                        // Dropping a runtime from within another one panics, so release it in the background
+                        runtime.shutdown_background();
+                        match result {
+                            Ok(Err(e)) => Err(format!("{:#}", e)),
+                            _ => Err("Server failed to start".to_string()),
+                        }
+                    }
+                }
+            },
+            Message::ServerStarted,
+        )
+    }
+
+    fn server_started(&mut self, result: Result<StartedServer, String>) -> Task<Message> {
+        self.server_starting = false;
+
+        let handle = match result {
+            Ok(started) => match started.take() {
+                Some(handle) => handle,
+                None => return Task::none(),
+            },
+            Err(e) => {
+                self.set_status(e);
+                if self.close_when_stopped {
+                    return window::get_latest().and_then(window::close);
+                }
+                return Task::none();
+            }
+        };
+        info!("GUI: FTP server started successfully with {} binding(s)", handle.server.bound_addresses().len());
+        let server_info = handle.server.server_info();
+
+        self.server_handle = Some(handle);
+        self.server_running = true;
+        self.set_status("Server running".to_string());
+        self.save_gui_state();
+
         self.server_info = text_editor::Content::with_text(&server_info.format_display_info());
 
+        // The window was closed while the server was starting
+        if self.close_when_stopped {
+            return self.stop_server();
+        }
         Task::none()
     }
 
@@ -235,46 +885,52 @@ impl SixFtpGui {
             return Task::none();
         }
 
-        // Shutdown the server by calling the shutdown method
-        if let Some(handle_arc) = self.server_handle.take() {
-            // Try to unwrap the Arc - if there are other references, this will just drop our reference
-            if let Ok(handle_mutex) = Arc::try_unwrap(handle_arc) {
-                if let Ok(handle) = handle_mutex.into_inner() {
-                    // Call the shutdown method which will abort tasks and leak the runtime
-                    handle.shutdown();
-                }
-            }
-        }
+        let Some(handle) = self.server_handle.take() else {
+            return Task::none();
+        };
 
+        // Start stays disabled until the ports are released
         self.server_running = false;
-        self.server_status = "Server stopped".to_string();
+        self.server_stopping = true;
         self.server_info = text_editor::Content::new();
-        self.server_status_content = text_editor::Content::with_text(&self.server_status);
+        self.sessions.clear();
+        self.bans.clear();
+        self.set_status("Stopping server, waiting for active transfers to finish...".to_string());
 
-        info!("GUI: FTP server stopped");
+        handle.shutdown()
+    }
 
-        Task::none()
+    fn kick_session(&mut self, id: u64, ban: bool) {
+        // The session list refreshes itself through the subscription once the session is gone
+        if let Some(handle) = &self.server_handle
+            && !handle.server.sessions().kick(id, ban)
+        {
+            info!("GUI: Session {} had already disconnected", id);
+        }
     }
-}
 
-fn parse_pasv_range(range_str: &str) -> Result<std::ops::RangeInclusive<u16>> {
-    let parts: Vec<&str> = range_str.split('-').collect();
-    if parts.len() != 2 {
-        return Err(anyhow::anyhow!("Invalid passive port range format. Use 'start-end'"));
+    fn unban(&mut self, ip: IpAddr) {
+        if let Some(handle) = &self.server_handle {
+            handle.server.sessions().bans().unban(ip);
+        }
     }
 
-    let start: u16 = parts[0].parse()?;
-    let end: u16 = parts[1].parse()?;
+    fn server_stopped(&mut self) -> Task<Message> {
+        self.server_stopping = false;
+        self.set_status("Server stopped".to_string());
 
-    if start > end {
-        return Err(anyhow::anyhow!("Passive port range start must be less than or equal to end"));
-    }
+        info!("GUI: FTP server stopped");
 
-    if end - start > 100 {
-        return Err(anyhow::anyhow!("Passive port range too large. Maximum 100 ports allowed"));
+        if self.close_when_stopped {
+            return window::get_latest().and_then(window::close);
+        }
+        Task::none()
     }
+}
 
-    Ok(start..=end)
+/// Items of a comma-separated input field, skipping empty ones
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|item| !item.is_empty())
 }
 
 pub fn update(state: &mut SixFtpGui, message: Message) -> Task<Message> {
@@ -303,8 +959,210 @@ pub fn update(state: &mut SixFtpGui, message: Message) -> Task<Message> {
             state.bind_address = addr;
             Task::none()
         }
+        Message::ShutdownTimeoutChanged(timeout) => {
+            state.shutdown_timeout = timeout;
+            Task::none()
+        }
+        Message::PassiveHostChanged(host) => {
+            state.passive_host = host;
+            Task::none()
+        }
+        Message::PassiveHostEchoChanged(echo) => {
+            state.passive_host_echo = echo;
+            Task::none()
+        }
+        Message::PortMappingSelected(method) => {
+            state.port_mapping = method;
+            Task::none()
+        }
+        Message::PortMappingGatewayChanged(gateway) => {
+            state.port_mapping_gateway = gateway;
+            Task::none()
+        }
+        Message::LanOnlyToggled(lan_only) => {
+            state.lan_only = lan_only;
+            Task::none()
+        }
+        Message::AllowChanged(ranges) => {
+            state.allow = ranges;
+            Task::none()
+        }
+        Message::DenyChanged(ranges) => {
+            state.deny = ranges;
+            Task::none()
+        }
+        Message::BanAfterChanged(count) => {
+            state.ban_after = count;
+            Task::none()
+        }
+        Message::BanWindowChanged(seconds) => {
+            state.ban_window = seconds;
+            Task::none()
+        }
+        Message::BanDurationChanged(seconds) => {
+            state.ban_duration = seconds;
+            Task::none()
+        }
+        Message::UsersFileChanged(path) => {
+            state.users_file = path;
+            Task::none()
+        }
+        Message::LoadUsersFile => {
+            state.load_users_file();
+            Task::none()
+        }
+        Message::SaveUsersFile => {
+            state.save_users_file();
+            Task::none()
+        }
+        Message::NewUserNameChanged(name) => {
+            state.new_user_name = name;
+            Task::none()
+        }
+        Message::NewUserPasswordChanged(pass) => {
+            state.new_user_password = pass;
+            Task::none()
+        }
+        Message::NewUserHomeChanged(home) => {
+            state.new_user_home = home;
+            Task::none()
+        }
+        Message::NewUserReadToggled(read) => {
+            state.new_user_read = read;
+            Task::none()
+        }
+        Message::NewUserWriteToggled(write) => {
+            state.new_user_write = write;
+            Task::none()
+        }
+        Message::AddUser => {
+            state.add_user();
+            Task::none()
+        }
+        Message::RemoveUser(index) => {
+            if index < state.accounts.len() {
+                let removed = state.accounts.remove(index);
+                state.set_status(format!("Removed user '{}', save the users file to keep the change", removed.username));
+            }
+            Task::none()
+        }
+        Message::TlsCertChanged(path) => {
+            state.tls_cert = path;
+            Task::none()
+        }
+        Message::TlsKeyChanged(path) => {
+            state.tls_key = path;
+            Task::none()
+        }
+        Message::TlsRequiredToggled(required) => {
+            state.tls_required = required;
+            Task::none()
+        }
+        Message::TlsSelfSignedToggled(self_signed) => {
+            state.tls_self_signed = self_signed;
+            Task::none()
+        }
+        Message::TlsImplicitPortChanged(port) => {
+            state.tls_implicit_port = port;
+            Task::none()
+        }
+        Message::ReadOnlyToggled(read_only) => {
+            state.read_only = read_only;
+            if read_only {
+                state.drop_box = false;
+            }
+            Task::none()
+        }
+        Message::ProfileSelected(name) => {
+            state.select_profile(name);
+            Task::none()
+        }
+        Message::AnonymousToggled(anonymous) => {
+            state.anonymous = anonymous;
+            Task::none()
+        }
+        Message::AnonymousReadOnlyToggled(read_only) => {
+            state.anonymous_read_only = read_only;
+            Task::none()
+        }
+        Message::AnonymousDirChanged(dir) => {
+            state.anonymous_dir = dir;
+            Task::none()
+        }
+        Message::MaxConnectionsChanged(max) => {
+            state.max_connections = max;
+            Task::none()
+        }
+        Message::MaxConnectionsPerIpChanged(max) => {
+            state.max_connections_per_ip = max;
+            Task::none()
+        }
+        Message::MaxDownloadRateChanged(rate) => {
+            state.max_download_rate = rate;
+            Task::none()
+        }
+        Message::MaxUploadRateChanged(rate) => {
+            state.max_upload_rate = rate;
+            Task::none()
+        }
+        Message::MaxUserDownloadRateChanged(rate) => {
+            state.max_user_download_rate = rate;
+            Task::none()
+        }
+        Message::MaxUserUploadRateChanged(rate) => {
+            state.max_user_upload_rate = rate;
+            Task::none()
+        }
+        Message::AuditLogChanged(path) => {
+            state.audit_log = path;
+            Task::none()
+        }
+        Message::DropBoxToggled(drop_box) => {
+            state.drop_box = drop_box;
+            if drop_box {
+                state.read_only = false;
+            }
+            Task::none()
+        }
         Message::StartServer => state.start_server(),
         Message::StopServer => state.stop_server(),
+        Message::ServerStarted(result) => state.server_started(result),
+        Message::ServerStopped => state.server_stopped(),
+        Message::SessionsUpdated(sessions, bans) => {
+            state.sessions = sessions;
+            state.bans = bans;
+            Task::none()
+        }
+        Message::OpenLogFile => {
+            if let Some(path) = logging::log_file()
+                && let Err(e) = logging::open_in_system_viewer(path)
+            {
+                state.set_status(format!("Failed to open log file {}: {}", path.display(), e));
+            }
+            Task::none()
+        }
+        Message::LogReceived(entries) => {
+            state.log_entries.extend(entries);
+            let excess = state.log_entries.len().saturating_sub(logging::GUI_LOG_CAPACITY);
+            state.log_entries.drain(..excess);
+            Task::none()
+        }
+        Message::LogLevelSelected(level) => {
+            state.log_level = level;
+            Task::none()
+        }
+        Message::KickSession(id) => {
+            state.kick_session(id, false);
+            Task::none()
+        }
+        Message::BanSession(id) => {
+            state.kick_session(id, true);
+            Task::none()
+        }
+        Message::Unban(ip) => {
+            state.unban(ip);
+            Task::none()
+        }
         Message::ServerInfoEdited(action) => {
             // Allow text selection by performing the action
             // Users can edit the text, but text selection is more important
@@ -316,9 +1174,12 @@ pub fn update(state: &mut SixFtpGui, message: Message) -> Task<Message> {
             if let Event::Window(window::Event::CloseRequested) = event {
                 info!("GUI: Window close requested, stopping server gracefully");
 
-                // Stop the server if it's running
-                if state.server_running {
-                    let _ = state.stop_server();
+                state.save_gui_state();
+
+                // Let active transfers finish first, the window closes once the server has stopped
+                if state.server_running || state.server_starting || state.server_stopping {
+                    state.close_when_stopped = true;
+                    return state.stop_server();
                 }
 
                 // Close the window
@@ -330,7 +1191,19 @@ pub fn update(state: &mut SixFtpGui, message: Message) -> Task<Message> {
 }
 
 pub fn view(state: &SixFtpGui) -> Element<'_, Message> {
-    let title = text("SixFTP Server").size(24);
+    let profiles = state.config_file.profile_names();
+    let title = if profiles.is_empty() {
+        row![text("SixFTP Server").size(24)]
+    } else {
+        row![
+            text("SixFTP Server").size(24).width(Length::Fill),
+            text("Profile:"),
+            pick_list(profiles, state.selected_profile.clone(), Message::ProfileSelected)
+                .placeholder("Select profile")
+        ]
+        .spacing(10)
+        .align_y(iced::Alignment::Center)
+    };
 
     let directory_input = column![
         text("Directory to serve:"),
@@ -378,10 +1251,255 @@ pub fn view(state: &SixFtpGui) -> Element<'_, Message> {
                 .padding(10)
         ]
         .spacing(3)
+        .width(Length::Fill),
+        column![
+            text("Shutdown Timeout (s):"),
+            text_input("Seconds", &state.shutdown_timeout)
+                .on_input(Message::ShutdownTimeoutChanged)
+                .padding(10)
+        ]
+        .spacing(3)
+        .width(Length::Fill)
+    ].spacing(15);
+
+    let passive_host_row = row![
+        column![
+            text("Passive Host (IPv4, DNS name or auto, empty = connection address):"),
+            text_input("e.g. ftp.example.com", &state.passive_host)
+                .on_input(Message::PassiveHostChanged)
+                .padding(10)
+        ]
+        .spacing(3)
+        .width(Length::Fill),
+        column![
+            text("Public Address Echo (for auto without port mapping):"),
+            text_input(&format!("e.g. {} (third-party)", passive_host::EXAMPLE_ECHO), &state.passive_host_echo)
+                .on_input(Message::PassiveHostEchoChanged)
+                .padding(10)
+        ]
+        .spacing(3)
+        .width(Length::Fill)
+    ]
+    .spacing(15);
+
+    let port_mapping_row = row![
+        column![
+            text("Router Port Mapping:"),
+            pick_list(PortMappingMethod::ALL, Some(state.port_mapping), Message::PortMappingSelected).padding(10)
+        ]
+        .spacing(3),
+        column![
+            text("Router Address (empty = default gateway, or a UPnP description URL):"),
+            text_input("e.g. 192.168.1.1", &state.port_mapping_gateway)
+                .on_input(Message::PortMappingGatewayChanged)
+                .padding(10)
+        ]
+        .spacing(3)
+        .width(Length::Fill)
+    ]
+    .spacing(15);
+
+    let tls_row = row![
+        column![
+            text("TLS Certificate (PEM, optional):"),
+            text_input("Path to certificate", &state.tls_cert)
+                .on_input(Message::TlsCertChanged)
+                .padding(10)
+        ]
+        .spacing(3)
+        .width(Length::Fill),
+        column![
+            text("TLS Private Key (PEM):"),
+            text_input("Path to private key", &state.tls_key)
+                .on_input(Message::TlsKeyChanged)
+                .padding(10)
+        ]
+        .spacing(3)
+        .width(Length::Fill),
+        column![
+            text("Implicit FTPS port:"),
+            text_input("Optional", &state.tls_implicit_port)
+                .on_input(Message::TlsImplicitPortChanged)
+                .padding(10)
+        ]
+        .spacing(3)
+        .width(140),
+        column![
+            checkbox("Self-signed", state.tls_self_signed).on_toggle(Message::TlsSelfSignedToggled),
+            checkbox("Require TLS", state.tls_required).on_toggle(Message::TlsRequiredToggled)
+        ]
+        .spacing(5)
+    ]
+    .spacing(15)
+    .align_y(iced::Alignment::End);
+
+    let ip_rules_row = row![
+        checkbox("LAN only", state.lan_only).on_toggle(Message::LanOnlyToggled),
+        column![
+            text("Allowed Addresses (CIDR, comma-separated, empty = all):"),
+            text_input("e.g. 192.168.0.0/16, 2001:db8::/48", &state.allow)
+                .on_input(Message::AllowChanged)
+                .padding(10)
+        ]
+        .spacing(3)
+        .width(Length::Fill),
+        column![
+            text("Denied Addresses (CIDR, comma-separated):"),
+            text_input("e.g. 192.168.1.13", &state.deny)
+                .on_input(Message::DenyChanged)
+                .padding(10)
+        ]
+        .spacing(3)
+        .width(Length::Fill)
+    ]
+    .spacing(15)
+    .align_y(iced::Alignment::End);
+
+    let protection_row = row![
+        column![
+            text("Ban After Failed Logins (0 = never):"),
+            text_input("Count", &state.ban_after)
+                .on_input(Message::BanAfterChanged)
+                .padding(10)
+        ]
+        .spacing(3)
+        .width(Length::Fill),
+        column![
+            text("Within (s):"),
+            text_input("Seconds", &state.ban_window)
+                .on_input(Message::BanWindowChanged)
+                .padding(10)
+        ]
+        .spacing(3)
+        .width(Length::Fill),
+        column![
+            text("Ban Duration (s):"),
+            text_input("Seconds", &state.ban_duration)
+                .on_input(Message::BanDurationChanged)
+                .padding(10)
+        ]
+        .spacing(3)
         .width(Length::Fill)
     ].spacing(15);
 
-    let server_control = if state.server_running {
+    let rates_row = row![
+        column![
+            text("Max Download Rate (all clients):"),
+            text_input("Unlimited, e.g. 5MiB/s", &state.max_download_rate)
+                .on_input(Message::MaxDownloadRateChanged)
+                .padding(10)
+        ]
+        .spacing(3)
+        .width(Length::Fill),
+        column![
+            text("Max Upload Rate (all clients):"),
+            text_input("Unlimited", &state.max_upload_rate)
+                .on_input(Message::MaxUploadRateChanged)
+                .padding(10)
+        ]
+        .spacing(3)
+        .width(Length::Fill),
+        column![
+            text("Max Download Rate per User:"),
+            text_input("Unlimited", &state.max_user_download_rate)
+                .on_input(Message::MaxUserDownloadRateChanged)
+                .padding(10)
+        ]
+        .spacing(3)
+        .width(Length::Fill),
+        column![
+            text("Max Upload Rate per User:"),
+            text_input("Unlimited", &state.max_user_upload_rate)
+                .on_input(Message::MaxUserUploadRateChanged)
+                .padding(10)
+        ]
+        .spacing(3)
+        .width(Length::Fill)
+    ].spacing(15);
+
+    let options_row = row![
+        checkbox("Read-only (no uploads, deletes or renames)", state.read_only).on_toggle(Message::ReadOnlyToggled),
+        checkbox("Drop box (upload new files only)", state.drop_box).on_toggle(Message::DropBoxToggled),
+        text_input("Audit log file (optional, JSON lines)", &state.audit_log)
+            .on_input(Message::AuditLogChanged)
+            .padding(10),
+        text_input("Max clients", &state.max_connections)
+            .on_input(Message::MaxConnectionsChanged)
+            .padding(10)
+            .width(120),
+        text_input("Max per IP", &state.max_connections_per_ip)
+            .on_input(Message::MaxConnectionsPerIpChanged)
+            .padding(10)
+            .width(120)
+    ]
+    .spacing(15)
+    .align_y(iced::Alignment::Center);
+
+    let anonymous_row = row![
+        checkbox("Allow anonymous login", state.anonymous).on_toggle(Message::AnonymousToggled),
+        checkbox("Anonymous read-only", state.anonymous_read_only).on_toggle(Message::AnonymousReadOnlyToggled),
+        text_input("Anonymous subdirectory (optional)", &state.anonymous_dir)
+            .on_input(Message::AnonymousDirChanged)
+            .padding(10)
+    ]
+    .spacing(15)
+    .align_y(iced::Alignment::Center);
+
+    let users_file_row = row![
+        column![
+            text("Users File (optional, overrides username/password):"),
+            text_input("Path to users.toml", &state.users_file)
+                .on_input(Message::UsersFileChanged)
+                .padding(10)
+        ]
+        .spacing(3)
+        .width(Length::Fill),
+        button("Load").on_press(Message::LoadUsersFile),
+        button("Save").on_press(Message::SaveUsersFile)
+    ]
+    .spacing(15)
+    .align_y(iced::Alignment::End);
+
+    let accounts_list = Column::with_children(state.accounts.iter().enumerate().map(|(index, account)| {
+        row![
+            text(account.summary()).width(Length::Fill),
+            button("Remove").on_press(Message::RemoveUser(index))
+        ]
+        .spacing(15)
+        .align_y(iced::Alignment::Center)
+        .into()
+    }))
+    .spacing(5);
+
+    let new_user_row = row![
+        text_input("New username", &state.new_user_name)
+            .on_input(Message::NewUserNameChanged)
+            .padding(10),
+        text_input("Password", &state.new_user_password)
+            .on_input(Message::NewUserPasswordChanged)
+            .secure(true)
+            .padding(10),
+        text_input("Home (optional)", &state.new_user_home)
+            .on_input(Message::NewUserHomeChanged)
+            .padding(10),
+        checkbox("Read", state.new_user_read).on_toggle(Message::NewUserReadToggled),
+        checkbox("Write", state.new_user_write).on_toggle(Message::NewUserWriteToggled),
+        button("Add User").on_press(Message::AddUser)
+    ]
+    .spacing(15)
+    .align_y(iced::Alignment::Center);
+
+    let users_section = column![
+        users_file_row,
+        accounts_list,
+        new_user_row
+    ].spacing(8);
+
+    let server_control = if state.server_starting {
+        button("Starting...")
+    } else if state.server_stopping {
+        button("Stopping...")
+    } else if state.server_running {
         button("Stop Server")
             .on_press(Message::StopServer)
     } else {
@@ -417,16 +1535,147 @@ pub fn view(state: &SixFtpGui) -> Element<'_, Message> {
 
     };
 
-    let content = column![
-        title,
+    let clients_panel: Element<'_, Message> = if state.server_running {
+        let clients = if state.sessions.is_empty() {
+            column![text("No clients logged in")]
+        } else {
+            Column::with_children(state.sessions.iter().map(|session| {
+                row![
+                    text(session.summary()).width(Length::Fill),
+                    button("Kick").on_press(Message::KickSession(session.id)),
+                    button("Kick & Ban IP").on_press(Message::BanSession(session.id))
+                ]
+                .spacing(15)
+                .align_y(iced::Alignment::Center)
+                .into()
+            }))
+            .spacing(5)
+        };
+
+        // Logged-in clients, and with limits the open connections against them, e.g.
+        // "2, 3 connections / 50, max 2 per IP"
+        let registry = state.server_handle.as_ref().map(|h| h.server.sessions());
+        let limits = registry.map(|r| r.limits()).unwrap_or_default();
+        let mut count = state.sessions.len().to_string();
+        if limits.max.is_some() || limits.per_ip.is_some() {
+            let connections = registry.map(|r| r.connection_count()).unwrap_or_default();
+            count.push_str(&format!(", {} connections", connections));
+        }
+        if let Some(max) = limits.max {
+            count.push_str(&format!(" / {}", max));
+        }
+        if let Some(per_ip) = limits.per_ip {
+            count.push_str(&format!(", max {} per IP", per_ip));
+        }
+
+        let bans: Element<'_, Message> = if state.bans.is_empty() {
+            Space::with_height(0).into()
+        } else {
+            column![
+                Space::with_height(15),
+                text(format!("Banned Addresses ({}):", state.bans.len())).size(18),
+                Space::with_height(8),
+                scrollable(
+                    Column::with_children(state.bans.iter().map(|ban| {
+                        row![
+                            text(ban.summary()).width(Length::Fill),
+                            button("Unban").on_press(Message::Unban(ban.address))
+                        ]
+                        .spacing(15)
+                        .align_y(iced::Alignment::Center)
+                        .into()
+                    }))
+                    .spacing(5)
+                )
+                .height(80)
+                .width(Length::Fill)
+            ]
+            .into()
+        };
+
+        column![
+            Space::with_height(15),
+            text(format!("Connected Clients ({}):", count)).size(18),
+            Space::with_height(8),
+            scrollable(clients).height(150).width(Length::Fill),
+            bans
+        ]
+        .into()
+    } else {
+        Space::with_height(0).into()
+    };
+
+    let log_lines = Column::with_children(
+        state
+            .log_entries
+            .iter()
+            .filter(|entry| entry.level <= state.log_level)
+            .map(|entry| {
+                let line = text(entry.line()).size(12);
+                match entry.level {
+                    Level::ERROR => line.style(text::danger),
+                    Level::WARN => line,
+                    _ => line.style(text::secondary),
+                }
+                .into()
+            }),
+    )
+    .spacing(2);
+
+    let log_panel = column![
         Space::with_height(15),
+        row![
+            text("Log:").size(18),
+            pick_list(
+                [Level::ERROR, Level::WARN, Level::INFO, Level::DEBUG, Level::TRACE],
+                Some(state.log_level),
+                Message::LogLevelSelected
+            )
+        ]
+        .spacing(15)
+        .align_y(iced::Alignment::Center),
+        Space::with_height(8),
+        scrollable(log_lines).anchor_bottom().height(150).width(Length::Fill)
+    ];
+
+    // The settings scroll, so the window fits a 1080p screen with the status and log below them
+    let settings = column![
         directory_input,
         Space::with_height(8),
         credentials_row,
         Space::with_height(8),
         network_row,
+        Space::with_height(8),
+        passive_host_row,
+        Space::with_height(8),
+        port_mapping_row,
+        Space::with_height(8),
+        tls_row,
+        Space::with_height(8),
+        rates_row,
+        Space::with_height(8),
+        ip_rules_row,
+        Space::with_height(8),
+        protection_row,
+        Space::with_height(8),
+        options_row,
+        Space::with_height(8),
+        anonymous_row,
+        Space::with_height(8),
+        users_section
+    ]
+    .padding(iced::padding::right(15));
+
+    let content = column![
+        title,
+        Space::with_height(15),
+        scrollable(settings).height(Length::FillPortion(2)).width(Length::Fill),
         Space::with_height(20),
-        server_control,
+        row![
+            server_control,
+            button("Open Log File").on_press_maybe(logging::log_file().map(|_| Message::OpenLogFile))
+        ]
+        .spacing(15),
         Space::with_height(20),
         text("Server Status:").size(18).style(|_theme| {
             text::Style {
@@ -434,7 +1683,9 @@ pub fn view(state: &SixFtpGui) -> Element<'_, Message> {
             }
         }),
         Space::with_height(8),
-        status_box
+        status_box,
+        clients_panel,
+        log_panel
     ]
     .spacing(0)
     .padding(20)
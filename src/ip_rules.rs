@@ -0,0 +1,234 @@
+use anyhow::Result;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use crate::network_info::AddressScope;
+
+/// An IPv4 or IPv6 address range such as `192.168.0.0/16` or `2001:db8::/48`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Dual-stack sockets report IPv4 clients as IPv4-mapped IPv6 addresses
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(network).into(), u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(network), u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the top `prefix` of `bits` bits are the same in both addresses
+fn prefix_matches(network: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+    let shift = bits - prefix;
+    shift >= 128 || network >> shift == ip >> shift
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    /// Parse `address/prefix`, or a single address without a prefix
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let invalid = || anyhow::anyhow!("Invalid address range '{}', use e.g. 192.168.0.0/16 or 2001:db8::/48", s);
+
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = address
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .map_err(|_| invalid())?;
+
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|p| *p <= bits).ok_or_else(invalid)?,
+            None => bits,
+        };
+
+        // Clients are matched by their canonical address, so store IPv4-mapped ranges as IPv4
+        if let IpAddr::V6(v6) = network
+            && let Some(v4) = v6.to_ipv4_mapped()
+            && prefix >= 96
+        {
+            return Ok(Self { network: IpAddr::V4(v4), prefix: prefix - 96 });
+        }
+        Ok(Self { network, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Which client addresses may connect. Deny rules win over allow rules; with no allow rules every
+/// address that isn't denied is allowed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpRules {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+    /// Refuse every address that isn't loopback, link-local, private IPv4 or IPv6 unique local
+    pub lan_only: bool,
+}
+
+impl IpRules {
+    /// Parse allow and deny lists given as CIDR strings
+    pub fn parse(allow: &[String], deny: &[String], lan_only: bool) -> Result<Self> {
+        let parse = |ranges: &[String]| {
+            ranges
+                .iter()
+                .filter(|range| !range.trim().is_empty())
+                .map(|range| range.parse())
+                .collect::<Result<Vec<Cidr>>>()
+        };
+        Ok(Self {
+            allow: parse(allow)?,
+            deny: parse(deny)?,
+            lan_only,
+        })
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.lan_only && !AddressScope::of(ip).is_lan() {
+            return false;
+        }
+        if self.deny.iter().any(|range| range.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip))
+    }
+
+    /// Description for the server info banner, `None` when every address is allowed
+    pub fn describe(&self) -> Option<String> {
+        let join = |ranges: &[Cidr]| ranges.iter().map(Cidr::to_string).collect::<Vec<_>>().join(", ");
+        let mut rules = Vec::new();
+        if self.lan_only {
+            rules.push("local network only".to_string());
+        }
+        if !self.allow.is_empty() {
+            rules.push(format!("only from {}", join(&self.allow)));
+        }
+        if !self.deny.is_empty() {
+            rules.push(format!("never from {}", join(&self.deny)));
+        }
+        (!rules.is_empty()).then(|| rules.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_ranges_and_single_addresses() {
+        assert_eq!(cidr("192.168.0.0/16").to_string(), "192.168.0.0/16");
+        assert_eq!(cidr(" 2001:db8::/48 ").to_string(), "2001:db8::/48");
+        assert_eq!(cidr("[2001:db8::]/32").to_string(), "2001:db8::/32");
+        assert_eq!(cidr("10.1.2.3").to_string(), "10.1.2.3/32");
+        assert_eq!(cidr("::1").to_string(), "::1/128");
+        assert_eq!(cidr("0.0.0.0/0").to_string(), "0.0.0.0/0");
+        assert_eq!(cidr("::/0").to_string(), "::/0");
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        for invalid in ["", "10.0.0.0/", "10.0.0.0/33", "2001:db8::/129", "10.0.0.0/-1", "10.0.0/8", "host/8", "10.0.0.0/8/8"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{} should be invalid", invalid);
+        }
+    }
+
+    #[test]
+    fn stores_ipv4_mapped_ranges_as_ipv4() {
+        assert_eq!(cidr("::ffff:10.0.0.0/104"), cidr("10.0.0.0/8"));
+        assert_eq!(cidr("::ffff:192.168.1.7"), cidr("192.168.1.7/32"));
+        assert_eq!(cidr("::ffff:0.0.0.0/96"), cidr("0.0.0.0/0"));
+    }
+
+    #[test]
+    fn zero_prefix_matches_its_whole_family() {
+        let v4 = cidr("0.0.0.0/0");
+        assert!(v4.contains(ip("203.0.113.7")));
+        assert!(v4.contains(ip("255.255.255.255")));
+        assert!(v4.contains(ip("::ffff:203.0.113.7")));
+        assert!(!v4.contains(ip("2001:db8::1")));
+
+        let v6 = cidr("::/0");
+        assert!(v6.contains(ip("2001:db8::1")));
+        assert!(v6.contains(ip("::1")));
+        assert!(!v6.contains(ip("203.0.113.7")));
+        assert!(!v6.contains(ip("::ffff:203.0.113.7")));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_clients() {
+        let lan = cidr("192.168.1.0/24");
+        assert!(lan.contains(ip("::ffff:192.168.1.20")));
+        assert!(!lan.contains(ip("::ffff:192.168.2.20")));
+        assert!(cidr("::ffff:192.168.1.0/120").contains(ip("192.168.1.20")));
+    }
+
+    #[test]
+    fn respects_prefix_edges() {
+        let v4 = cidr("10.0.0.0/31");
+        assert!(v4.contains(ip("10.0.0.0")));
+        assert!(v4.contains(ip("10.0.0.1")));
+        assert!(!v4.contains(ip("10.0.0.2")));
+        assert!(!v4.contains(ip("9.255.255.255")));
+
+        let unaligned = cidr("172.16.5.4/12");
+        assert!(unaligned.contains(ip("172.31.255.255")));
+        assert!(!unaligned.contains(ip("172.32.0.0")));
+
+        let host = cidr("10.0.0.1/32");
+        assert!(host.contains(ip("10.0.0.1")));
+        assert!(!host.contains(ip("10.0.0.0")));
+
+        let v6 = cidr("2001:db8::/127");
+        assert!(v6.contains(ip("2001:db8::1")));
+        assert!(!v6.contains(ip("2001:db8::2")));
+
+        let v6_host = cidr("2001:db8::1/128");
+        assert!(v6_host.contains(ip("2001:db8::1")));
+        assert!(!v6_host.contains(ip("2001:db8::")));
+
+        let v6_one_bit = cidr("8000::/1");
+        assert!(v6_one_bit.contains(ip("ffff::1")));
+        assert!(!v6_one_bit.contains(ip("7fff::1")));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let rules = IpRules::parse(
+            &["192.168.0.0/16".to_string(), "2001:db8::/48".to_string()],
+            &["192.168.1.13".to_string()],
+            false,
+        )
+        .unwrap();
+        assert!(rules.permits(ip("192.168.1.12")));
+        assert!(!rules.permits(ip("192.168.1.13")));
+        assert!(!rules.permits(ip("::ffff:192.168.1.13")));
+        assert!(rules.permits(ip("2001:db8::5")));
+        assert!(!rules.permits(ip("10.0.0.1")));
+        assert!(IpRules::default().permits(ip("203.0.113.7")));
+    }
+}
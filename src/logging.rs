@@ -0,0 +1,282 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{self, Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+use crate::audit::timestamp;
+
+/// Rotated files kept next to the log file, named `<file>.1` (newest) to `<file>.5`
+const KEEP_ROTATED: u32 = 5;
+
+/// Most recent log lines kept for the GUI log pane
+pub const GUI_LOG_CAPACITY: usize = 1000;
+
+static LOG_FILE: OnceLock<PathBuf> = OnceLock::new();
+static GUI_LOG: OnceLock<LogBuffer> = OnceLock::new();
+
+/// When to start a new log file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    /// When the file reaches the maximum size
+    #[default]
+    Size,
+    /// At midnight UTC, or earlier if the file reaches the maximum size
+    Daily,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    pub rotation: LogRotation,
+    pub max_size: u64,
+}
+
+impl LogFileConfig {
+    /// Log file used by the GUI, whose console is hidden on Windows
+    pub fn gui_default() -> Option<Self> {
+        let path = dirs::data_local_dir()?.join("sixftp").join("sixftp.log");
+        Some(Self {
+            path,
+            rotation: LogRotation::Size,
+            max_size: 10 * 1024 * 1024,
+        })
+    }
+}
+
+/// Initialize logging to stdout and, if given, to a rotating log file. With `gui_log`, events are also
+/// kept in a buffer for the GUI log pane. The file is only used if it can be opened; stdout logging is
+/// set up either way.
+pub fn init(log_file: Option<LogFileConfig>, gui_log: bool) -> Result<()> {
+    // If RUST_LOG=debug is set, show all logs
+    // Otherwise, show our app logs and libunftp logs at INFO level
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| {
+            // Default filter: show our app's logs and libunftp logs at INFO level and above,
+            // suppress other library logs unless they are ERROR
+            EnvFilter::new("sixftp=info,libunftp=info,error")
+        });
+
+    let (file, error) = match log_file.map(|config| RotatingFile::open(config.clone()).map(|f| (config, f))) {
+        Some(Ok((config, file))) => {
+            let _ = LOG_FILE.set(config.path);
+            (Some(file), None)
+        }
+        Some(Err(e)) => (None, Some(e)),
+        None => (None, None),
+    };
+    let file_layer = file.map(|file| fmt::layer().with_ansi(false).with_writer(Mutex::new(file)));
+    let gui_layer = gui_log.then(|| GuiLogLayer(GUI_LOG.get_or_init(LogBuffer::default)));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
+        .with(file_layer)
+        .with(gui_layer)
+        .init();
+
+    match error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// The file logs are written to, if any
+pub fn log_file() -> Option<&'static Path> {
+    LOG_FILE.get().map(PathBuf::as_path)
+}
+
+/// Log lines collected for the GUI, if enabled in `init`
+pub fn gui_log() -> Option<&'static LogBuffer> {
+    GUI_LOG.get()
+}
+
+/// A log event as shown in the GUI
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    /// Increases by one per event, so readers can ask for what they haven't seen yet
+    pub sequence: u64,
+    pub time: String,
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+}
+
+impl LogEntry {
+    pub fn line(&self) -> String {
+        format!("{} {:>5} {}: {}", self.time, self.level, self.target, self.message)
+    }
+}
+
+/// The most recent log events, with a channel that is marked changed whenever one is added
+#[derive(Debug)]
+pub struct LogBuffer {
+    entries: Mutex<(u64, VecDeque<LogEntry>)>,
+    changed: watch::Sender<()>,
+}
+
+impl Default for LogBuffer {
+    fn default() -> Self {
+        Self {
+            entries: Mutex::default(),
+            changed: watch::Sender::new(()),
+        }
+    }
+}
+
+impl LogBuffer {
+    fn push(&self, level: Level, target: &str, message: String) {
+        let mut entries = self.entries.lock().unwrap();
+        let (next_sequence, lines) = &mut *entries;
+        *next_sequence += 1;
+        if lines.len() == GUI_LOG_CAPACITY {
+            lines.pop_front();
+        }
+        lines.push_back(LogEntry {
+            sequence: *next_sequence,
+            time: timestamp(SystemTime::now()),
+            level,
+            target: target.to_string(),
+            message,
+        });
+        drop(entries);
+
+        self.changed.send_replace(());
+    }
+
+    /// Entries newer than `sequence`
+    pub fn since(&self, sequence: u64) -> Vec<LogEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries.1.iter().filter(|e| e.sequence > sequence).cloned().collect()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
+}
+
+/// Forwards every event that passes the filter into the GUI's log buffer
+struct GuiLogLayer(&'static LogBuffer);
+
+impl<S: Subscriber> Layer<S> for GuiLogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: layer::Context<'_, S>) {
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        self.0.push(*event.metadata().level(), event.metadata().target(), visitor.message);
+    }
+}
+
+/// Formats an event as its message followed by any other fields as `name=value`
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            self.message.insert_str(0, &format!("{:?}", value));
+        } else {
+            self.message.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+}
+
+/// Open a file with the platform's default application
+pub fn open_in_system_viewer(path: &Path) -> io::Result<()> {
+    #[cfg(windows)]
+    let opener = "explorer";
+    #[cfg(target_os = "macos")]
+    let opener = "open";
+    #[cfg(all(unix, not(target_os = "macos")))]
+    let opener = "xdg-open";
+
+    std::process::Command::new(opener).arg(path).spawn().map(|_| ())
+}
+
+struct RotatingFile {
+    config: LogFileConfig,
+    file: File,
+    size: u64,
+    day: u64,
+}
+
+impl RotatingFile {
+    fn open(config: LogFileConfig) -> Result<Self> {
+        if let Some(dir) = config.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create '{}'", dir.display()))?;
+        }
+
+        let file = Self::open_file(&config.path)
+            .with_context(|| format!("Failed to open log file '{}'", config.path.display()))?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+
+        Ok(Self {
+            config,
+            file,
+            size,
+            day: current_day(),
+        })
+    }
+
+    fn open_file(path: &Path) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        // Windows can't rename onto an existing file, so make room first
+        let _ = std::fs::remove_file(self.rotated_path(KEEP_ROTATED));
+        for index in (1..KEEP_ROTATED).rev() {
+            let _ = std::fs::rename(self.rotated_path(index), self.rotated_path(index + 1));
+        }
+        std::fs::rename(&self.config.path, self.rotated_path(1))?;
+
+        self.file = Self::open_file(&self.config.path)?;
+        self.size = 0;
+        self.day = current_day();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let too_big = self.size > 0 && self.size + buf.len() as u64 > self.config.max_size;
+        let new_day = self.config.rotation == LogRotation::Daily && current_day() != self.day;
+        if (too_big || new_day) && self.rotate().is_err() {
+            // Keep appending to the current file rather than losing log lines, and retry later
+            self.size = 0;
+            self.day = current_day();
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Days since the Unix epoch in UTC
+fn current_day() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 86_400
+}
@@ -2,18 +2,33 @@
 #![cfg_attr(all(windows, not(debug_assertions)), windows_subsystem = "windows")]
 
 use anyhow::Result;
-use clap::Parser;
-use unftp_sbe_fs::ServerExt;
-use std::net::IpAddr;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
 use std::path::PathBuf;
+use std::time::Duration;
 use tracing::{info, error};
 use std::env;
 
 #[cfg(windows)]
 use windows::Win32::System::Console::{AllocConsole, AttachConsole, ATTACH_PARENT_PROCESS};
 
+mod audit;
+mod auth;
+mod bans;
+mod config;
+mod control;
 mod gui;
+mod ip_rules;
+mod logging;
 mod network_info;
+mod passive_host;
+mod port_mapping;
+mod server;
+mod sessions;
+mod storage;
+mod throttle;
+mod tls;
+mod users;
 
 
 
@@ -33,6 +48,38 @@ struct Args {
     #[arg(long, default_value = "password")]
     password: String,
 
+    /// Allow anonymous logins as 'anonymous' or 'ftp' with any password
+    #[arg(long)]
+    anonymous: bool,
+
+    /// Turn off anonymous logins enabled in the config file
+    #[arg(long, overrides_with = "anonymous")]
+    no_anonymous: bool,
+
+    /// Let anonymous users upload, rename and delete (anonymous access is read-only by default)
+    #[arg(long, requires = "anonymous")]
+    anonymous_write: bool,
+
+    /// Confine anonymous users to this subdirectory of the served directory
+    #[arg(long, requires = "anonymous", value_name = "SUBDIR")]
+    anonymous_dir: Option<PathBuf>,
+
+    /// Users file (TOML) with multiple accounts; overrides --username/--password
+    #[arg(long)]
+    users_file: Option<PathBuf>,
+
+    /// Print a bcrypt hash of the given password for use in a users file, then exit
+    #[arg(long, value_name = "PASSWORD")]
+    hash_password: Option<String>,
+
+    /// Config file [default: sixftp.toml in the platform config directory]
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Named profile from the config file (e.g. lan-share)
+    #[arg(long)]
+    profile: Option<String>,
+
     /// Main FTP port
     #[arg(short, long, default_value = "9000")]
     port: u16,
@@ -44,6 +91,149 @@ struct Args {
     /// Bind address
     #[arg(short, long, default_value = "0.0.0.0")]
     bind: String,
+
+    /// Address advertised for passive mode behind NAT: an IPv4 address, a DNS name resolved for
+    /// every transfer, or 'auto' to ask --passive-host-echo [default: the address clients connect to]
+    #[arg(long, value_name = "HOST")]
+    passive_host: Option<String>,
+
+    /// Service reporting the public IPv4 address for '--passive-host auto' when the router doesn't:
+    /// an http:// URL or a host:port that answers with the address as text [default: none]
+    #[arg(long, value_name = "URL")]
+    passive_host_echo: Option<String>,
+
+    /// Ask the router to forward the main port and the passive range while the server runs
+    /// (UPnP, NAT-PMP or PCP; auto tries PCP, NAT-PMP, then UPnP) [default without a value: auto]
+    #[arg(
+        long,
+        value_enum,
+        value_name = "METHOD",
+        default_value = "off",
+        num_args = 0..=1,
+        default_missing_value = "auto"
+    )]
+    port_mapping: port_mapping::PortMappingMethod,
+
+    /// Router to send port mapping requests to instead of the default gateway: an IPv4 address
+    /// (optionally with a port) or the http:// URL of a UPnP device description
+    #[arg(long, value_name = "ADDR")]
+    port_mapping_gateway: Option<String>,
+
+    /// TLS certificate chain (PEM) to enable explicit FTPS (AUTH TLS)
+    #[arg(long, requires = "tls_key", conflicts_with = "tls_self_signed")]
+    tls_cert: Option<PathBuf>,
+
+    /// TLS private key (PEM) matching --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Refuse uploads, deletes, renames and directory changes; listings and downloads still work
+    #[arg(long)]
+    read_only: bool,
+
+    /// Turn off read-only mode set in the config file
+    #[arg(long, overrides_with = "read_only")]
+    no_read_only: bool,
+
+    /// Upload-only drop box: clients can store new files but can't list, download or overwrite anything
+    #[arg(long, conflicts_with = "read_only")]
+    drop_box: bool,
+
+    /// Turn off drop box mode set in the config file
+    #[arg(long, overrides_with = "drop_box")]
+    no_drop_box: bool,
+
+    /// Enable FTPS with a generated self-signed certificate, cached and reused across restarts
+    #[arg(long)]
+    tls_self_signed: bool,
+
+    /// Turn off the self-signed certificate set in the config file
+    #[arg(long, overrides_with = "tls_self_signed")]
+    no_tls_self_signed: bool,
+
+    /// Refuse logins and transfers that don't use TLS
+    #[arg(long)]
+    tls_required: bool,
+
+    /// Accept logins and transfers without TLS even if the config file requires it
+    #[arg(long, overrides_with = "tls_required")]
+    no_tls_required: bool,
+
+    /// Also accept implicit FTPS (TLS from the first byte) on this port
+    #[arg(long, value_name = "PORT")]
+    tls_implicit_port: Option<u16>,
+
+    /// Append a JSON lines audit trail of logins, logouts and file operations to this file
+    #[arg(long, value_name = "FILE")]
+    audit_log: Option<PathBuf>,
+
+    /// Also write logs to this file
+    #[arg(long, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+
+    /// When to start a new log file; old files are kept as <FILE>.1 to <FILE>.5
+    #[arg(long, value_enum, default_value = "size", requires = "log_file")]
+    log_rotation: logging::LogRotation,
+
+    /// Start a new log file once it reaches this many MiB
+    #[arg(long, default_value = "10", value_name = "MIB", requires = "log_file")]
+    log_max_size: u64,
+
+    /// Limit the total download rate of all clients (e.g. 5MiB/s, 500KiB/s)
+    #[arg(long, value_name = "RATE")]
+    max_download_rate: Option<String>,
+
+    /// Limit the total upload rate of all clients
+    #[arg(long, value_name = "RATE")]
+    max_upload_rate: Option<String>,
+
+    /// Limit the download rate of each user, shared by all of the user's sessions
+    #[arg(long, value_name = "RATE")]
+    max_user_download_rate: Option<String>,
+
+    /// Limit the upload rate of each user, shared by all of the user's sessions
+    #[arg(long, value_name = "RATE")]
+    max_user_upload_rate: Option<String>,
+
+    /// Only allow clients from these address ranges (e.g. 192.168.0.0/16,2001:db8::/48)
+    #[arg(long, value_name = "CIDR", value_delimiter = ',')]
+    allow: Vec<String>,
+
+    /// Refuse clients from these address ranges, even if they are allowed by --allow
+    #[arg(long, value_name = "CIDR", value_delimiter = ',')]
+    deny: Vec<String>,
+
+    /// Only accept clients from the local network and only listen on private/unique local addresses
+    #[arg(long)]
+    lan_only: bool,
+
+    /// Turn off LAN only mode set in the config file
+    #[arg(long, overrides_with = "lan_only")]
+    no_lan_only: bool,
+
+    /// Refuse connections once this many clients are connected
+    #[arg(long, value_name = "COUNT")]
+    max_connections: Option<usize>,
+
+    /// Refuse connections from an address that already has this many connections
+    #[arg(long, value_name = "COUNT")]
+    max_connections_per_ip: Option<usize>,
+
+    /// Ban an address after this many failed logins within --ban-window (0 to never ban)
+    #[arg(long, default_value = "5", value_name = "COUNT")]
+    ban_after: u32,
+
+    /// Seconds in which --ban-after failed logins trigger a ban
+    #[arg(long, default_value = "600", value_name = "SECONDS")]
+    ban_window: u64,
+
+    /// Seconds a banned address is refused
+    #[arg(long, default_value = "900", value_name = "SECONDS")]
+    ban_duration: u64,
+
+    /// Seconds to let active transfers finish when stopping the server
+    #[arg(long, default_value = "30", value_name = "SECONDS")]
+    shutdown_timeout: u64,
 }
 
 #[tokio::main]
@@ -63,25 +253,13 @@ async fn main() -> Result<()> {
         }
     }
 
-    // Initialize logging with custom configuration
-    // If RUST_LOG=debug is set, show all logs
-    // Otherwise, show our app logs and libunftp logs at INFO level
-    use tracing_subscriber::{EnvFilter, fmt};
-
-    let filter = EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| {
-            // Default filter: show our app's logs and libunftp logs at INFO level and above,
-            // suppress other library logs unless they are ERROR
-            EnvFilter::new("sixftp=info,libunftp=info,error")
-        });
-
-    fmt()
-        .with_env_filter(filter)
-        .init();
-
     // If no arguments provided (only program name), launch GUI
     // If any arguments are present (including -h for help), use CLI
     if args.len() == 1 {
+        // The console is hidden on Windows, so the GUI always logs to a file as well
+        if let Err(e) = logging::init(logging::LogFileConfig::gui_default(), true) {
+            error!("{:#}", e);
+        }
         return run_gui_mode().await;
     }
 
@@ -89,146 +267,94 @@ async fn main() -> Result<()> {
     run_cli_mode().await
 }
 
-async fn start_ftp_server(directory: &PathBuf, port: u16, bind_addr: &IpAddr, pasv_range: &std::ops::RangeInclusive<u16>) -> Result<Vec<IpAddr>> {
-    let mut successful_bindings = Vec::new();
-    let mut tasks = Vec::new();
-
-    // If bind address is unspecified (0.0.0.0 or ::), bind to both IPv4 and IPv6
-    if bind_addr.is_unspecified() {
-        // Try IPv4
-        let ipv4_bind = "0.0.0.0".parse::<IpAddr>().unwrap();
-        let bind_string = format!("{}:{}", ipv4_bind, port);
-
-        let server = libunftp::Server::with_fs(directory.clone())
-            .passive_ports(pasv_range.clone())
-            .passive_host(libunftp::options::PassiveHost::FromConnection)
-            .greeting("Welcome to QuickFTP Server")
-            .build()
-            .unwrap();
-        
-        let task = tokio::spawn(async move {
-            match server.listen(bind_string).await {
-                Ok(_) => {
-                    info!("FTP server stopped gracefully on IPv4");
-                    Some(ipv4_bind)
-                }
-                Err(e) => {
-                    error!("Failed to bind to IPv4 {}: {}", ipv4_bind, e);
-                    None
+/// Fill in settings from the config file for every flag not given on the command line
+fn apply_settings(args: &mut Args, settings: config::Settings, matches: &ArgMatches) -> Result<()> {
+    let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+    macro_rules! apply {
+        ($($field:ident),+ $(,)?) => {
+            $(
+                if !from_cli(stringify!($field))
+                    && let Some(value) = settings.$field
+                {
+                    args.$field = value;
                 }
-            }
-        });
-        tasks.push((task, ipv4_bind));
-
-        // Try IPv6
-        let ipv6_bind = "::".parse::<IpAddr>().unwrap();
-        let bind_string = format!("[{}]:{}", ipv6_bind, port);
-
-        let server = libunftp::Server::with_fs(directory.clone())
-            .passive_ports(pasv_range.clone())
-            .passive_host(libunftp::options::PassiveHost::FromConnection)
-            .greeting("Welcome to SixFTP Server")
-            .build()
-            .unwrap();
-        
-        let task = tokio::spawn(async move {
-            match server.listen(bind_string).await {
-                Ok(_) => {
-                    info!("FTP server stopped gracefully on IPv6");
-                    Some(ipv6_bind)
-                }
-                Err(e) => {
-                    error!("Failed to bind to IPv6 {}: {}", ipv6_bind, e);
-                    None
-                }
-            }
-        });
-        tasks.push((task, ipv6_bind));
-    } else {
-        // Use the specified bind address
-        let bind_string = if bind_addr.is_ipv6() {
-            format!("[{}]:{}", bind_addr, port)
-        } else {
-            format!("{}:{}", bind_addr, port)
+            )+
         };
+    }
 
-        let server = libunftp::Server::with_fs(directory.clone())
-            .passive_ports(pasv_range.clone())
-            .passive_host(libunftp::options::PassiveHost::FromConnection)
-            .greeting("Welcome to SixFTP Server")
-            .build()
-            .unwrap();
-        
-        let bind_addr_clone = *bind_addr;
-        
-        let task = tokio::spawn(async move {
-            match server.listen(bind_string).await {
-                Ok(_) => {
-                    info!("FTP server stopped gracefully on {}", bind_addr_clone);
-                    Some(bind_addr_clone)
+    macro_rules! apply_optional {
+        ($($field:ident),+ $(,)?) => {
+            $(
+                if !from_cli(stringify!($field))
+                    && let Some(value) = settings.$field
+                {
+                    args.$field = Some(value);
                 }
-                Err(e) => {
-                    error!("Failed to bind to {}: {}", bind_addr_clone, e);
-                    None
-                }
-            }
-        });
-        tasks.push((task, *bind_addr));
+            )+
+        };
     }
 
-    // Wait a bit for bindings to succeed or fail
-    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-
-    // Check which bindings succeeded
-    for (task, addr) in tasks {
-        if !task.is_finished() {
-            // If the task is still running, the binding succeeded
-            successful_bindings.push(addr);
-            info!("Successfully bound to {}:{}", addr, port);
-        }
+    // Switches the config file turns on can be turned off again with their --no-* flag
+    macro_rules! apply_switch {
+        ($($field:ident),+ $(,)?) => {
+            $(
+                if !from_cli(stringify!($field))
+                    && !from_cli(concat!("no_", stringify!($field)))
+                    && let Some(value) = settings.$field
+                {
+                    args.$field = value;
+                }
+            )+
+        };
     }
 
-    if successful_bindings.is_empty() {
-        return Err(anyhow::anyhow!("Failed to bind to any address on port {}", port));
+    apply!(
+        directory, username, password, port, pasv_range, bind, port_mapping, shutdown_timeout, log_rotation, log_max_size,
+        ban_after, ban_window, ban_duration, allow, deny,
+    );
+    apply_switch!(read_only, drop_box, anonymous, tls_self_signed, tls_required, lan_only);
+    apply_optional!(
+        passive_host, passive_host_echo, port_mapping_gateway, users_file, anonymous_dir, tls_cert, tls_key, tls_implicit_port, audit_log, log_file,
+        max_download_rate, max_upload_rate, max_user_download_rate, max_user_upload_rate,
+        max_connections, max_connections_per_ip,
+    );
+
+    // The config file keeps the GUI's "read-only" wording
+    if !from_cli("anonymous_write")
+        && let Some(read_only) = settings.anonymous_read_only
+    {
+        args.anonymous_write = !read_only;
     }
 
-    info!("FTP server started successfully on {} address(es)", successful_bindings.len());
-
-    Ok(successful_bindings)
-}
-
-fn parse_pasv_range(range_str: &str) -> Result<std::ops::RangeInclusive<u16>> {
-    let parts: Vec<&str> = range_str.split('-').collect();
-    if parts.len() != 2 {
-        return Err(anyhow::anyhow!("Invalid passive port range format. Use 'start-end' (e.g., 30000-30010)"));
+    // clap only sees conflicts between flags on the command line; one given there wins over the
+    // config file, and conflicting settings within the config file are an error
+    if from_cli("drop_box") && args.drop_box {
+        args.read_only = false;
     }
-
-    let start: u16 = parts[0].parse()?;
-    let end: u16 = parts[1].parse()?;
-
-    if start > end {
-        return Err(anyhow::anyhow!("Passive port range start must be less than or equal to end"));
+    if from_cli("read_only") && args.read_only {
+        args.drop_box = false;
     }
-
-    if end - start > 100 {
-        return Err(anyhow::anyhow!("Passive port range too large. Maximum 100 ports allowed"));
+    if args.read_only && args.drop_box {
+        return Err(anyhow::anyhow!("The config file sets both read_only and drop_box, pick one"));
     }
 
-    Ok(start..=end)
+    if from_cli("tls_self_signed") && args.tls_self_signed {
+        args.tls_cert = None;
+        args.tls_key = None;
+    }
+    if from_cli("tls_cert") {
+        args.tls_self_signed = false;
+    }
+    if args.tls_self_signed && args.tls_cert.is_some() {
+        return Err(anyhow::anyhow!("The config file sets both tls_cert and tls_self_signed, pick one"));
+    }
+    Ok(())
 }
 
-fn display_server_info(successful_bindings: &[IpAddr], port: u16, pasv_range: &std::ops::RangeInclusive<u16>, directory: &PathBuf, username: &str, password: &str) {
-    let server_info = network_info::ServerInfo {
-        successful_bindings: successful_bindings.to_vec(),
-        port,
-        pasv_range: pasv_range.clone(),
-        directory: directory.clone(),
-        username: username.to_string(),
-        password: password.to_string(),
-    };
-    
+fn display_server_info(server_info: &network_info::ServerInfo) {
     println!("{}", server_info.format_display_info());
-    println!("   Press Ctrl+C to stop the server\n");
+    println!("   Press Ctrl+C to stop the server, type 'help' for commands to list and kick clients\n");
 }
 
 
@@ -248,35 +374,246 @@ async fn run_gui_mode() -> Result<()> {
 
 /// Run the CLI version of the FTP server
 async fn run_cli_mode() -> Result<()> {
+    // Parse command line arguments for CLI mode, keeping the matches to tell which flags were given
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches)?;
+
+    if let Some(password) = &args.hash_password {
+        println!("{}", users::hash_password(password)?);
+        return Ok(());
+    }
+
+    // Settings from the config file apply unless overridden on the command line
+    let config_path = args.config.clone().or_else(config::default_config_path);
+    if let Some(config_path) = &config_path {
+        let config_file = config::ConfigFile::load(config_path)?;
+        let settings = config_file.resolve(args.profile.as_deref())?;
+        apply_settings(&mut args, settings, &matches)?;
+    } else if args.profile.is_some() {
+        return Err(anyhow::anyhow!("--profile needs a config file, pass one with --config"));
+    }
+
+    // The log file may come from the config file, so logging starts only now
+    logging::init(args.log_file.clone().map(|path| logging::LogFileConfig {
+        path,
+        rotation: args.log_rotation,
+        max_size: args.log_max_size * 1024 * 1024,
+    }), false)?;
     info!("Starting SixFTP CLI mode");
-    
-    // Parse command line arguments for CLI mode
-    let args = Args::parse();
+    if let Some(config_path) = &config_path {
+        info!("Using config file {}", config_path.display());
+    }
 
     // Validate and parse passive port range
-    let pasv_range = parse_pasv_range(&args.pasv_range)?;
+    let pasv_range = server::parse_pasv_range(&args.pasv_range, args.port)?;
 
     info!("Starting SixFTP server with passive port range: {} to {}", pasv_range.start(), pasv_range.end());
 
-    // Validate directory exists
-    if !args.directory.exists() {
-        return Err(anyhow::anyhow!("Directory '{}' does not exist", args.directory.display()));
+    let bind_addr = server::parse_bind_address(&args.bind)?;
+    let passive_host = passive_host::PassiveHostConfig::parse(
+        args.passive_host.as_deref().unwrap_or_default(),
+        args.passive_host_echo.as_deref(),
+    )?;
+    let port_mapping = port_mapping::PortMappingConfig::parse(args.port_mapping, args.port_mapping_gateway.as_deref())?;
+
+    // Load accounts from the users file if given, otherwise use the single username/password
+    let accounts = match &args.users_file {
+        Some(path) => {
+            let users_file = users::UsersFile::load(path)?;
+            if users_file.users.is_empty() {
+                return Err(anyhow::anyhow!("Users file '{}' contains no accounts", path.display()));
+            }
+            info!("Loaded {} account(s) from {}", users_file.users.len(), path.display());
+            users_file.users
+        }
+        None => Vec::new(),
+    };
+
+    let anonymous = if args.anonymous {
+        Some(auth::AnonymousAccess::new(!args.anonymous_write, args.anonymous_dir.clone())?)
+    } else {
+        None
+    };
+
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::TlsConfig::new(cert.clone(), key.clone(), args.tls_required)?),
+        _ if args.tls_self_signed => Some(tls::TlsConfig::self_signed(args.tls_required)?),
+        _ if args.tls_required => {
+            return Err(anyhow::anyhow!("--tls-required needs --tls-cert/--tls-key or --tls-self-signed"));
+        }
+        _ if args.tls_implicit_port.is_some() => {
+            return Err(anyhow::anyhow!("--tls-implicit-port needs --tls-cert/--tls-key or --tls-self-signed"));
+        }
+        _ => None,
     }
+    .map(|tls| tls.with_implicit_port(args.tls_implicit_port));
+
+    let parse_rate = |rate: &Option<String>| rate.as_deref().map(throttle::parse_rate).transpose().map(Option::flatten);
+    let rate_limits = throttle::RateLimits {
+        download: parse_rate(&args.max_download_rate)?,
+        upload: parse_rate(&args.max_upload_rate)?,
+        user_download: parse_rate(&args.max_user_download_rate)?,
+        user_upload: parse_rate(&args.max_user_upload_rate)?,
+    };
 
-    // Parse bind address (strip brackets from IPv6 addresses if present)
-    let bind_address_cleaned = args.bind.trim()
-        .trim_start_matches('[')
-        .trim_end_matches(']');
-    let bind_addr: IpAddr = bind_address_cleaned.parse()?;
+    let ip_rules = ip_rules::IpRules::parse(&args.allow, &args.deny, args.lan_only)?;
+
+    let access_mode = if args.read_only {
+        storage::AccessMode::ReadOnly
+    } else if args.drop_box {
+        storage::AccessMode::DropBox
+    } else {
+        storage::AccessMode::ReadWrite
+    };
 
     // Try to bind to all interfaces (IPv4 and IPv6)
-    let successful_bindings = start_ftp_server(&args.directory, args.port, &bind_addr, &pasv_range).await?;
+    let handle = server::start(server::ServerConfig {
+        directory: args.directory.clone(),
+        port: args.port,
+        bind_addr,
+        pasv_range,
+        passive_host,
+        port_mapping,
+        username: args.username.clone(),
+        password: args.password.clone(),
+        accounts,
+        anonymous,
+        access_mode,
+        tls,
+        shutdown_timeout: Duration::from_secs(args.shutdown_timeout),
+        audit_log: args.audit_log.clone(),
+        rate_limits,
+        connection_limits: sessions::ConnectionLimits {
+            max: args.max_connections,
+            per_ip: args.max_connections_per_ip,
+        },
+        login_protection: bans::LoginProtection {
+            max_failures: args.ban_after,
+            window: Duration::from_secs(args.ban_window),
+            ban_duration: Duration::from_secs(args.ban_duration),
+        },
+        ip_rules,
+    })
+    .await?;
 
     // Display server information with successful bindings
-    display_server_info(&successful_bindings, args.port, &pasv_range, &args.directory, &args.username, &args.password);
+    display_server_info(&handle.server_info());
 
-    // Wait for all servers to finish
-    tokio::time::sleep(tokio::time::Duration::from_secs(u64::MAX)).await;
+    // Serve until asked to stop, then let active transfers finish
+    tokio::select! {
+        _ = shutdown_signal() => info!("Shutdown requested"),
+        _ = control::run(handle.sessions().clone()) => info!("Stop requested from the console"),
+    }
+    handle.shutdown().await;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Wait for Ctrl+C, or SIGTERM on Unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => error!("Failed to listen for SIGTERM: {}", e),
+        }
+    }
+
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("Failed to listen for Ctrl+C: {}", e);
+        // Without a way to be told to stop, keep serving until the process is killed
+        std::future::pending::<()>().await;
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse `cli` and apply `settings` from a config file, as `run_cli_mode` does
+    fn parse(cli: &[&str], settings: config::Settings) -> Result<Args> {
+        let matches = Args::command().try_get_matches_from(std::iter::once("sixftp").chain(cli.iter().copied()))?;
+        let mut args = Args::from_arg_matches(&matches)?;
+        apply_settings(&mut args, settings, &matches)?;
+        Ok(args)
+    }
+
+    fn switches_on() -> config::Settings {
+        config::Settings {
+            read_only: Some(true),
+            anonymous: Some(true),
+            tls_self_signed: Some(true),
+            tls_required: Some(true),
+            lan_only: Some(true),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn config_switches_apply() {
+        let args = parse(&[], switches_on()).unwrap();
+        assert!(args.read_only && args.anonymous && args.tls_self_signed && args.tls_required && args.lan_only);
+    }
+
+    #[test]
+    fn no_flags_turn_off_config_switches() {
+        let cli = ["--no-read-only", "--no-anonymous", "--no-tls-self-signed", "--no-tls-required", "--no-lan-only"];
+        let args = parse(&cli, switches_on()).unwrap();
+        assert!(!args.read_only && !args.anonymous && !args.tls_self_signed && !args.tls_required && !args.lan_only);
+
+        let drop_box = config::Settings { drop_box: Some(true), ..Default::default() };
+        assert!(!parse(&["--no-drop-box"], drop_box).unwrap().drop_box);
+    }
+
+    #[test]
+    fn last_of_a_flag_and_its_negation_wins() {
+        assert!(parse(&["--no-read-only", "--read-only"], config::Settings::default()).unwrap().read_only);
+        assert!(!parse(&["--read-only", "--no-read-only"], switches_on()).unwrap().read_only);
+    }
+
+    #[test]
+    fn cli_access_mode_wins_over_the_config_file() {
+        let args = parse(&["--drop-box"], config::Settings { read_only: Some(true), ..Default::default() }).unwrap();
+        assert!(args.drop_box && !args.read_only);
+
+        let args = parse(&["--read-only"], config::Settings { drop_box: Some(true), ..Default::default() }).unwrap();
+        assert!(args.read_only && !args.drop_box);
+    }
+
+    #[test]
+    fn cli_certificate_choice_wins_over_the_config_file() {
+        let certificate = config::Settings {
+            tls_cert: Some(PathBuf::from("cert.pem")),
+            tls_key: Some(PathBuf::from("key.pem")),
+            ..Default::default()
+        };
+        let args = parse(&["--tls-self-signed"], certificate).unwrap();
+        assert!(args.tls_self_signed && args.tls_cert.is_none() && args.tls_key.is_none());
+
+        let self_signed = config::Settings { tls_self_signed: Some(true), ..Default::default() };
+        let args = parse(&["--tls-cert", "cert.pem", "--tls-key", "key.pem"], self_signed).unwrap();
+        assert!(!args.tls_self_signed);
+        assert_eq!(args.tls_cert, Some(PathBuf::from("cert.pem")));
+    }
+
+    #[test]
+    fn refuses_conflicting_config_settings() {
+        let access = config::Settings { read_only: Some(true), drop_box: Some(true), ..Default::default() };
+        assert!(parse(&[], access).is_err());
+
+        let tls = config::Settings {
+            tls_cert: Some(PathBuf::from("cert.pem")),
+            tls_key: Some(PathBuf::from("key.pem")),
+            tls_self_signed: Some(true),
+            ..Default::default()
+        };
+        assert!(parse(&[], tls).is_err());
+    }
+}
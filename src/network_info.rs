@@ -7,13 +7,91 @@ pub struct NetworkIps {
     pub ipv6: Vec<Ipv6Addr>,
 }
 
+/// How far an address reaches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressScope {
+    Loopback,
+    /// 169.254.0.0/16 and fe80::/10, only reachable on the local link
+    LinkLocal,
+    /// RFC 1918 IPv4 ranges and IPv6 unique local addresses (fc00::/7)
+    Private,
+    /// Internet-routable, e.g. IPv6 global unicast (2000::/3)
+    Global,
+    /// Unspecified, multicast and other special addresses
+    Other,
+}
+
+impl AddressScope {
+    pub fn of(ip: IpAddr) -> Self {
+        match ip.to_canonical() {
+            IpAddr::V4(ipv4) => {
+                if ipv4.is_loopback() {
+                    AddressScope::Loopback
+                } else if ipv4.is_link_local() {
+                    AddressScope::LinkLocal
+                } else if ipv4.is_private() {
+                    AddressScope::Private
+                } else if ipv4.is_unspecified() || ipv4.is_multicast() || ipv4.is_broadcast() {
+                    AddressScope::Other
+                } else {
+                    AddressScope::Global
+                }
+            }
+            IpAddr::V6(ipv6) => {
+                let first = ipv6.segments()[0];
+                if ipv6.is_loopback() {
+                    AddressScope::Loopback
+                } else if (0xFE80..=0xFEBF).contains(&first) {
+                    AddressScope::LinkLocal
+                } else if (0xFC00..=0xFDFF).contains(&first) {
+                    AddressScope::Private
+                } else if (0x2000..=0x3FFF).contains(&first) {
+                    AddressScope::Global
+                } else {
+                    AddressScope::Other
+                }
+            }
+        }
+    }
+
+    /// Whether the address can only be reached from the local network
+    pub fn is_lan(&self) -> bool {
+        matches!(self, AddressScope::Loopback | AddressScope::LinkLocal | AddressScope::Private)
+    }
+}
+
 pub struct ServerInfo {
     pub successful_bindings: Vec<IpAddr>,
     pub port: u16,
     pub pasv_range: std::ops::RangeInclusive<u16>,
+    /// Passive ports that could be bound at startup
+    pub pasv_available: usize,
+    /// Address advertised in PASV replies, `None` when it's the address the client connected to
+    pub passive_address: Option<String>,
+    /// Ports mapped on the router, and why mapping failed
+    pub port_mapping: Option<String>,
+    pub port_mapping_error: Option<String>,
     pub directory: std::path::PathBuf,
     pub username: String,
     pub password: String,
+    /// Summaries of accounts loaded from a users file; empty when a single username/password is used
+    pub accounts: Vec<String>,
+    /// FTPS mode description, `None` when serving plaintext FTP only
+    pub tls: Option<String>,
+    /// SHA-256 fingerprint of the TLS certificate so clients can verify it
+    pub tls_fingerprint: Option<String>,
+    /// Server-wide access restriction, `None` when clients may read and write
+    pub access: Option<String>,
+    /// Anonymous login description, `None` when anonymous access is disabled
+    pub anonymous: Option<String>,
+    /// Bandwidth limits, `None` when transfers are unlimited
+    pub rate_limits: Option<String>,
+    /// Maximum concurrent clients, `None` when unlimited
+    pub connection_limits: Option<String>,
+    /// Failed login ban policy, `None` when disabled
+    pub login_protection: Option<String>,
+    /// Allowed and denied client address ranges, `None` when every address may connect
+    pub ip_rules: Option<String>,
 }
 
 impl ServerInfo {
@@ -23,6 +101,17 @@ impl ServerInfo {
         info.push_str("SixFTP Server Started\n");
         info.push_str("==========================\n\n");
 
+        if let Some(anonymous) = &self.anonymous {
+            info.push_str(&format!("*** ANONYMOUS ACCESS ENABLED: {} ***\n\n", anonymous));
+        }
+
+        // Only embed credentials in URLs when there is a single known account
+        let creds = if self.accounts.is_empty() {
+            format!("{}:{}@", self.username, self.password)
+        } else {
+            String::new()
+        };
+
         // Show actual network addresses for clients to use
         if let Ok(network_ips) = get_network_ips() {
             if !network_ips.ipv4.is_empty() || !network_ips.ipv6.is_empty() {
@@ -31,38 +120,36 @@ impl ServerInfo {
                 // Show IPv4 addresses
                 for ip in &network_ips.ipv4 {
                     info.push_str(&format!(
-                        "   - ftp://{}:{}@{}:{}\n",
-                        self.username, self.password, ip, self.port
+                        "   - ftp://{}{}:{}\n",
+                        creds, ip, self.port
                     ));
                 }
 
                 // Show IPv6 addresses with temporary address detection
                 for ip in &network_ips.ipv6 {
-                    let segments = ip.segments();
-                    let is_global = segments[0] >= 0x2000 && segments[0] <= 0x3FFF;
-                    let is_unique_local = segments[0] >= 0xFC00 && segments[0] <= 0xFDFF;
+                    let scope = AddressScope::of(IpAddr::V6(*ip));
 
-                    if is_global {
+                    if scope == AddressScope::Global {
                         if is_temporary_ipv6(ip) {
                             info.push_str(&format!(
-                                "   - ftp://{}:{}@[{}]:{} (temporary)\n",
-                                self.username, self.password, ip, self.port
+                                "   - ftp://{}[{}]:{} (temporary)\n",
+                                creds, ip, self.port
                             ));
                         } else {
                             info.push_str(&format!(
-                                "   - ftp://{}:{}@[{}]:{} (public)\n",
-                                self.username, self.password, ip, self.port
+                                "   - ftp://{}[{}]:{} (public)\n",
+                                creds, ip, self.port
                             ));
                         }
-                    } else if is_unique_local {
+                    } else if scope == AddressScope::Private {
                         info.push_str(&format!(
-                            "   - ftp://{}:{}@[{}]:{} (private)\n",
-                            self.username, self.password, ip, self.port
+                            "   - ftp://{}[{}]:{} (private)\n",
+                            creds, ip, self.port
                         ));
                     } else {
                         info.push_str(&format!(
-                            "   - ftp://{}:{}@[{}]:{}\n",
-                            self.username, self.password, ip, self.port
+                            "   - ftp://{}[{}]:{}\n",
+                            creds, ip, self.port
                         ));
                     }
                 }
@@ -75,13 +162,13 @@ impl ServerInfo {
         for bind_addr in &self.successful_bindings {
             if bind_addr.is_ipv6() {
                 info.push_str(&format!(
-                    "   - ftp://{}:{}@[{}]:{}\n",
-                    self.username, self.password, bind_addr, self.port
+                    "   - ftp://{}[{}]:{}\n",
+                    creds, bind_addr, self.port
                 ));
             } else {
                 info.push_str(&format!(
-                    "   - ftp://{}:{}@{}:{}\n",
-                    self.username, self.password, bind_addr, self.port
+                    "   - ftp://{}{}:{}\n",
+                    creds, bind_addr, self.port
                 ));
             }
         }
@@ -90,14 +177,62 @@ impl ServerInfo {
             "\nServing directory: {}\n",
             self.directory.display()
         ));
-        info.push_str(&format!("Username: {}\n", self.username));
-        info.push_str(&format!("Password: {}\n", self.password));
+        if let Some(access) = &self.access {
+            info.push_str(&format!("Access: {}\n", access));
+        }
+        if self.accounts.is_empty() {
+            info.push_str(&format!("Username: {}\n", self.username));
+            info.push_str(&format!("Password: {}\n", self.password));
+        } else {
+            info.push_str("Users:\n");
+            for account in &self.accounts {
+                info.push_str(&format!("   - {}\n", account));
+            }
+        }
+        if let Some(ip_rules) = &self.ip_rules {
+            info.push_str(&format!("Allowed clients: {}\n", ip_rules));
+        }
+        if let Some(connection_limits) = &self.connection_limits {
+            info.push_str(&format!("Connection limits: {}\n", connection_limits));
+        }
+        if let Some(login_protection) = &self.login_protection {
+            info.push_str(&format!("Login protection: {}\n", login_protection));
+        }
+        if let Some(rate_limits) = &self.rate_limits {
+            info.push_str(&format!("Rate limits: {}\n", rate_limits));
+        }
+        match &self.tls {
+            Some(tls) => {
+                info.push_str(&format!("Encryption: {}\n", tls));
+                if let Some(fingerprint) = &self.tls_fingerprint {
+                    info.push_str(&format!("Certificate SHA-256 fingerprint:\n   {}\n", fingerprint));
+                }
+            }
+            None => info.push_str("Encryption: none (plaintext FTP, credentials are sent unencrypted)\n"),
+        }
         info.push_str(&format!(
-            "Passive ports: {} to {}\n",
+            "Passive ports: {} to {}",
             self.pasv_range.start(),
             self.pasv_range.end()
         ));
-        info.push_str("Make sure to forward the main and passive port range in your firewall/router if needed.\n");
+        let pasv_total = self.pasv_range.clone().count();
+        if self.pasv_available < pasv_total {
+            info.push_str(&format!(" ({} of {} available, the rest are in use)", self.pasv_available, pasv_total));
+        }
+        info.push('\n');
+        if let Some(passive_address) = &self.passive_address {
+            info.push_str(&format!("Passive address: {}\n", passive_address));
+        }
+        match (&self.port_mapping, &self.port_mapping_error) {
+            (Some(port_mapping), _) => info.push_str(&format!("Port mapping: {}\n", port_mapping)),
+            (None, Some(error)) => {
+                info.push_str(&format!("Port mapping failed: {}\n", error));
+                info.push_str("Forward the main and passive port range in your firewall/router instead if needed.\n");
+            }
+            (None, None) => info.push_str(
+                "Make sure to forward the main and passive port range in your firewall/router if needed.\n",
+            ),
+        }
         info.push_str("\nConnect using any FTP client with the displayed addresses\n");
 
         info
@@ -127,18 +262,8 @@ pub fn get_network_ips() -> Result<NetworkIps> {
                     // - Global unicast addresses (public IPv6) - starts with 2000::/3
                     // - Unique local addresses (private IPv6) - starts with fc00::/7
                     // Skip link-local (fe80::/10) and loopback
-                    if !ipv6.is_loopback() && !ipv6.is_unspecified() {
-                        let segments = ipv6.segments();
-                        // Check for global unicast (2000::/3)
-                        let is_global = segments[0] >= 0x2000 && segments[0] <= 0x3FFF;
-                        // Check for unique local (fc00::/7)
-                        let is_unique_local = segments[0] >= 0xFC00 && segments[0] <= 0xFDFF;
-                        // Check for link-local (fe80::/10)
-                        let is_link_local = segments[0] >= 0xFE80 && segments[0] <= 0xFEBF;
-
-                        if (is_global || is_unique_local) && !is_link_local {
-                            ipv6_ips.push(ipv6);
-                        }
+                    if matches!(AddressScope::of(IpAddr::V6(ipv6)), AddressScope::Global | AddressScope::Private) {
+                        ipv6_ips.push(ipv6);
                     }
                 }
             }
@@ -185,19 +310,10 @@ fn get_ipv6_interfaces() -> Result<Vec<Ipv6Addr>> {
         for (_, ip) in interfaces {
             if let IpAddr::V6(ipv6) = ip {
                 // Include global unicast (public) and unique local (private) IPv6 addresses
-                let segments = ipv6.segments();
-                let is_global = segments[0] >= 0x2000 && segments[0] <= 0x3FFF;
-                let is_unique_local = segments[0] >= 0xFC00 && segments[0] <= 0xFDFF;
-                let is_link_local = segments[0] >= 0xFE80 && segments[0] <= 0xFEBF;
-
-                if (is_global || is_unique_local)
-                    && !ipv6.is_loopback()
-                    && !ipv6.is_unspecified()
-                    && !is_link_local
+                if matches!(AddressScope::of(IpAddr::V6(ipv6)), AddressScope::Global | AddressScope::Private)
+                    && !ipv6_addresses.contains(&ipv6)
                 {
-                    if !ipv6_addresses.contains(&ipv6) {
-                        ipv6_addresses.push(ipv6);
-                    }
+                    ipv6_addresses.push(ipv6);
                 }
             }
         }
@@ -216,10 +332,8 @@ fn is_temporary_ipv6(ipv6: &Ipv6Addr) -> bool {
     // The universal/local bit is bit 6 (counting from 0) in the interface identifier
     // In the last segment (segments[7]), this is bit 6 of the 16-bit value
 
-    // Check if this is a global unicast address (starts with 2000::/3)
-    let is_global_unicast = segments[0] >= 0x2000 && segments[0] <= 0x3FFF;
-
-    if !is_global_unicast {
+    // Only global unicast addresses (2000::/3) use privacy extensions
+    if AddressScope::of(IpAddr::V6(*ipv6)) != AddressScope::Global {
         return false;
     }
 
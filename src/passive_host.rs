@@ -0,0 +1,159 @@
+use anyhow::{Context, Result};
+use libunftp::options::PassiveHost;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::info;
+
+/// Suggested in messages and the GUI only. It's a third-party service reached over plain HTTP, so it's
+/// never asked unless the user names it.
+pub const EXAMPLE_ECHO: &str = "http://api.ipify.org/";
+
+const ECHO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Address advertised to clients in PASV replies
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum PassiveHostConfig {
+    /// The server address the client connected to, which is wrong behind NAT
+    #[default]
+    FromConnection,
+    Ip(Ipv4Addr),
+    /// Resolved for every PASV reply, so dynamic DNS names stay current
+    Dns(String),
+    /// Public address reported by the router or by the configured echo service when the server starts
+    Auto { echo: Option<String> },
+}
+
+impl PassiveHostConfig {
+    /// Parse `--passive-host`: empty for the connection address, an IPv4 address, a DNS name or `auto`.
+    /// `echo` is the echo service used by `auto` when the router doesn't report the address.
+    pub fn parse(host: &str, echo: Option<&str>) -> Result<Self> {
+        match host.trim() {
+            "" => Ok(PassiveHostConfig::FromConnection),
+            "auto" => {
+                let echo = echo.map(str::trim).filter(|echo| !echo.is_empty()).map(str::to_string);
+                Ok(PassiveHostConfig::Auto { echo })
+            }
+            host => match host.parse::<IpAddr>() {
+                Ok(IpAddr::V4(ip)) => Ok(PassiveHostConfig::Ip(ip)),
+                Ok(IpAddr::V6(_)) => Err(anyhow::anyhow!(
+                    "Passive host '{}' must be an IPv4 address, PASV replies can't carry IPv6 (IPv6 clients use EPSV)",
+                    host
+                )),
+                Err(_) if is_hostname(host) => Ok(PassiveHostConfig::Dns(host.to_string())),
+                Err(_) => Err(anyhow::anyhow!(
+                    "Invalid passive host '{}', use an IPv4 address, a DNS name or 'auto'",
+                    host
+                )),
+            },
+        }
+    }
+
+    /// The libunftp setting and a description of the advertised address for the server info (`None`
+    /// when it's the connection address). `auto` uses the public address the router reported while
+    /// mapping ports if there is one, and asks the echo service otherwise; with neither it fails.
+    pub async fn resolve(&self, router_address: Option<Ipv4Addr>) -> Result<(PassiveHost, Option<String>)> {
+        match self {
+            PassiveHostConfig::FromConnection => Ok((PassiveHost::FromConnection, None)),
+            PassiveHostConfig::Ip(ip) => Ok((PassiveHost::Ip(*ip), Some(ip.to_string()))),
+            PassiveHostConfig::Dns(name) => Ok((
+                PassiveHost::Dns(name.clone()),
+                Some(format!("{} (resolved for every transfer)", name)),
+            )),
+            PassiveHostConfig::Auto { .. } if let Some(ip) = router_address => {
+                Ok((PassiveHost::Ip(ip), Some(format!("{} (reported by the router)", ip))))
+            }
+            PassiveHostConfig::Auto { echo: None } => Err(anyhow::anyhow!(
+                "Passive host 'auto' needs the router's address (port mapping) or an echo service; set \
+                 --passive-host-echo to one you trust, e.g. a service on your router or LAN, or the \
+                 third-party {}",
+                EXAMPLE_ECHO
+            )),
+            PassiveHostConfig::Auto { echo: Some(echo) } => {
+                let ip = detect_public_ipv4(echo)
+                    .await
+                    .with_context(|| format!("Failed to detect the public address for passive mode via {}", echo))?;
+                info!("Detected public address {} via {}", ip, echo);
+                Ok((PassiveHost::Ip(ip), Some(format!("{} (detected via {})", ip, echo))))
+            }
+        }
+    }
+}
+
+fn is_hostname(host: &str) -> bool {
+    host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Ask an echo service for this machine's public IPv4 address. `echo` is either an `http://` URL
+/// that answers with the address as plain text, or the `host:port` of a TCP service that writes
+/// the address and closes the connection.
+pub async fn detect_public_ipv4(echo: &str) -> Result<Ipv4Addr> {
+    let (authority, request) = match echo.trim().strip_prefix("http://") {
+        Some(rest) => {
+            let (authority, path) = match rest.find('/') {
+                Some(index) => rest.split_at(index),
+                None => (rest, "/"),
+            };
+            let request = format!(
+                "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: SixFTP\r\nConnection: close\r\n\r\n",
+                path, authority
+            );
+            let authority = if authority.contains(':') { authority.to_string() } else { format!("{}:80", authority) };
+            (authority, Some(request))
+        }
+        None => (echo.trim().to_string(), None),
+    };
+
+    // The whole exchange, name lookup included, is bounded so a dead resolver can't stall the start
+    let exchange = async {
+        // PASV can only carry IPv4, so the echo service has to see us over IPv4
+        let addr = tokio::net::lookup_host(&authority)
+            .await
+            .with_context(|| format!("Failed to resolve '{}'", authority))?
+            .find(SocketAddr::is_ipv4)
+            .ok_or_else(|| anyhow::anyhow!("'{}' has no IPv4 address", authority))?;
+
+        let talk = async {
+            let mut stream = TcpStream::connect(addr).await?;
+            if let Some(request) = &request {
+                stream.write_all(request.as_bytes()).await?;
+            }
+            let mut response = Vec::new();
+            stream.take(64 * 1024).read_to_end(&mut response).await?;
+            Ok::<_, std::io::Error>(response)
+        };
+        let response = talk.await.with_context(|| format!("Failed to talk to {}", addr))?;
+        Ok::<_, anyhow::Error>((addr, response))
+    };
+    let (addr, response) = tokio::time::timeout(ECHO_TIMEOUT, exchange)
+        .await
+        .map_err(|_| anyhow::anyhow!("No answer from {} within {}s", authority, ECHO_TIMEOUT.as_secs()))??;
+    let response = String::from_utf8_lossy(&response);
+
+    let answer = match request {
+        Some(_) => {
+            let (head, body) = response
+                .split_once("\r\n\r\n")
+                .ok_or_else(|| anyhow::anyhow!("Invalid HTTP response from {}", addr))?;
+            let status = head.lines().next().unwrap_or_default();
+            if status.split_whitespace().nth(1) != Some("200") {
+                return Err(anyhow::anyhow!("Echo service answered '{}'", status));
+            }
+            body
+        }
+        None => &response,
+    };
+
+    answer.trim().parse().map_err(|_| {
+        let shown: String = answer.trim().chars().take(64).collect();
+        anyhow::anyhow!("Echo service answered '{}' instead of an IPv4 address", shown)
+    })
+}
@@ -0,0 +1,1310 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Instant;
+use tracing::{info, warn};
+use crate::network_info::AddressScope;
+
+/// Lifetime requested for every mapping. Routers may grant less, mappings are renewed at half of
+/// what they granted.
+pub const LIFETIME: Duration = Duration::from_secs(7200);
+
+/// Shortest time between renewals, in case a router grants next to nothing
+const MIN_RENEWAL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Mapping requests in flight at once, enough for a wide passive range without flooding the router
+const CONCURRENT_REQUESTS: usize = 8;
+
+/// Give up on the remaining ports after this many failed requests in a row, the router isn't answering
+const MAX_FAILURES_IN_A_ROW: usize = 5;
+
+/// Ports mapped at most, the rest of a wider passive range has to be forwarded by hand
+const MAX_PORTS: usize = 256;
+
+/// Time allowed for deleting the mappings, the router drops them at the end of their lifetime anyway
+const REMOVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// NAT-PMP and PCP servers listen on this port of the gateway
+const NAT_PMP_PORT: u16 = 5351;
+
+/// First NAT-PMP/PCP retransmission delay, doubled for every retry (RFC 6886)
+const UDP_RETRY: Duration = Duration::from_millis(250);
+const UDP_ATTEMPTS: u32 = 4;
+
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+const SSDP_WAIT: Duration = Duration::from_secs(2);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// UPnP error for routers that only accept mappings without a lease duration
+const ONLY_PERMANENT_LEASES: u32 = 725;
+
+/// How to ask the router to forward the control port and the passive range
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PortMappingMethod {
+    /// Forward the ports by hand
+    #[default]
+    Off,
+    /// PCP, then NAT-PMP, then UPnP
+    Auto,
+    Upnp,
+    NatPmp,
+    Pcp,
+}
+
+impl PortMappingMethod {
+    pub const ALL: [PortMappingMethod; 5] = [
+        PortMappingMethod::Off,
+        PortMappingMethod::Auto,
+        PortMappingMethod::Upnp,
+        PortMappingMethod::NatPmp,
+        PortMappingMethod::Pcp,
+    ];
+}
+
+impl fmt::Display for PortMappingMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PortMappingMethod::Off => "Off",
+            PortMappingMethod::Auto => "Auto",
+            PortMappingMethod::Upnp => "UPnP",
+            PortMappingMethod::NatPmp => "NAT-PMP",
+            PortMappingMethod::Pcp => "PCP",
+        })
+    }
+}
+
+/// Where to send mapping requests instead of the discovered gateway
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Gateway {
+    /// NAT-PMP/PCP server; for UPnP only devices at this address are used
+    Address(SocketAddr),
+    /// UPnP device description, skipping discovery
+    Description(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PortMappingConfig {
+    pub method: PortMappingMethod,
+    /// `None` to use the default gateway (NAT-PMP/PCP) or SSDP discovery (UPnP)
+    pub gateway: Option<Gateway>,
+}
+
+impl PortMappingConfig {
+    /// Parse `--port-mapping-gateway`: empty for discovery, an IPv4 address with an optional port
+    /// for NAT-PMP/PCP, or the `http://` URL of a UPnP device description
+    pub fn parse(method: PortMappingMethod, gateway: Option<&str>) -> Result<Self> {
+        let gateway = match gateway.map(str::trim).unwrap_or_default() {
+            "" => None,
+            url if url.starts_with("http://") => {
+                if matches!(method, PortMappingMethod::NatPmp | PortMappingMethod::Pcp) {
+                    return Err(anyhow::anyhow!(
+                        "{} needs a gateway address, '{}' is a UPnP description URL",
+                        method, url
+                    ));
+                }
+                Some(Gateway::Description(url.to_string()))
+            }
+            address => {
+                let invalid = || {
+                    anyhow::anyhow!(
+                        "Invalid gateway '{}', use an IPv4 address (e.g. 192.168.1.1) or a UPnP description URL",
+                        address
+                    )
+                };
+                let address = match address.parse::<SocketAddr>() {
+                    Ok(address) => address,
+                    Err(_) => SocketAddr::new(address.parse().map_err(|_| invalid())?, NAT_PMP_PORT),
+                };
+                if !address.is_ipv4() {
+                    return Err(invalid());
+                }
+                Some(Gateway::Address(address))
+            }
+        };
+        Ok(Self { method, gateway })
+    }
+
+    /// Map `ports` (TCP, external port same as the internal one) on the router. The first port is
+    /// required, later ones that the router refuses are left out. Returns `None` when disabled.
+    pub async fn map(&self, ports: &[u16]) -> Result<Option<PortMapping>> {
+        let methods: &[PortMappingMethod] = match self.method {
+            PortMappingMethod::Off => return Ok(None),
+            PortMappingMethod::Auto => match &self.gateway {
+                Some(Gateway::Description(_)) => &[PortMappingMethod::Upnp],
+                _ => &[PortMappingMethod::Pcp, PortMappingMethod::NatPmp, PortMappingMethod::Upnp],
+            },
+            method => &[method],
+        };
+
+        let mut errors = Vec::new();
+        for method in methods {
+            let attempt = async {
+                let client = self.client(*method).await?;
+                PortMapping::create(client, ports).await
+            };
+            match attempt.await {
+                Ok(mapping) => return Ok(Some(mapping)),
+                Err(e) => errors.push(format!("{}: {:#}", method, e)),
+            }
+        }
+        Err(anyhow::anyhow!("{}", errors.join("; ")))
+    }
+
+    async fn client(&self, method: PortMappingMethod) -> Result<Client> {
+        let gateway = match &self.gateway {
+            Some(Gateway::Address(address)) => Some(*address),
+            _ => None,
+        };
+        match method {
+            PortMappingMethod::Pcp | PortMappingMethod::NatPmp => {
+                let gateway = match gateway {
+                    Some(gateway) => gateway,
+                    None => SocketAddr::new(IpAddr::V4(default_gateway()?), NAT_PMP_PORT),
+                };
+                if method == PortMappingMethod::NatPmp {
+                    return Ok(Client::NatPmp { gateway });
+                }
+                Ok(Client::Pcp {
+                    gateway,
+                    local: local_address_towards(gateway.ip()).await?,
+                    nonce: random_nonce(),
+                })
+            }
+            _ => {
+                let location = match &self.gateway {
+                    Some(Gateway::Description(url)) => url.clone(),
+                    _ => discover_upnp(gateway.map(|g| g.ip())).await?,
+                };
+                upnp_client(&location).await
+            }
+        }
+    }
+}
+
+/// How mapping requests reach the router
+#[derive(Debug, Clone)]
+enum Client {
+    Pcp {
+        gateway: SocketAddr,
+        /// Our address as the gateway sees it, which PCP requests have to carry
+        local: Ipv4Addr,
+        /// Identifies our mappings so they can be renewed and deleted
+        nonce: [u8; 12],
+    },
+    NatPmp {
+        gateway: SocketAddr,
+    },
+    Upnp {
+        control_url: String,
+        service_type: String,
+        local: Ipv4Addr,
+    },
+}
+
+impl Client {
+    fn method(&self) -> PortMappingMethod {
+        match self {
+            Client::Pcp { .. } => PortMappingMethod::Pcp,
+            Client::NatPmp { .. } => PortMappingMethod::NatPmp,
+            Client::Upnp { .. } => PortMappingMethod::Upnp,
+        }
+    }
+
+    fn gateway(&self) -> String {
+        match self {
+            Client::Pcp { gateway, .. } | Client::NatPmp { gateway } => gateway.ip().to_string(),
+            Client::Upnp { control_url, .. } => split_url(control_url)
+                .map(|(authority, _)| authority.to_string())
+                .unwrap_or_else(|_| control_url.clone()),
+        }
+    }
+
+    /// Map `port` for `lifetime`, or delete the mapping with a zero lifetime
+    async fn request(&self, port: u16, lifetime: Duration) -> Result<Grant> {
+        // PASV replies advertise the internal port, so a different external port is useless
+        let refuse_other_port = |mapped: u16| {
+            anyhow::anyhow!("Router offered external port {} for port {}", mapped, port)
+        };
+        match self {
+            Client::Pcp { gateway, local, nonce } => {
+                let (mapped, external, granted) = pcp_map(*gateway, *local, nonce, port, lifetime).await?;
+                if !lifetime.is_zero() && mapped != port {
+                    let _ = pcp_map(*gateway, *local, nonce, port, Duration::ZERO).await;
+                    return Err(refuse_other_port(mapped));
+                }
+                Ok(Grant { external_address: Some(external), lifetime: granted })
+            }
+            Client::NatPmp { gateway } => {
+                let (mapped, granted) = nat_pmp_map(*gateway, port, lifetime).await?;
+                if !lifetime.is_zero() && mapped != port {
+                    let _ = nat_pmp_map(*gateway, port, Duration::ZERO).await;
+                    return Err(refuse_other_port(mapped));
+                }
+                Ok(Grant { external_address: None, lifetime: granted })
+            }
+            Client::Upnp { control_url, service_type, local } => {
+                // UPnP doesn't report a lease, a permanent one is renewed as if it was requested
+                if lifetime.is_zero() {
+                    upnp_delete(control_url, service_type, port).await?;
+                } else {
+                    upnp_add(control_url, service_type, *local, port, lifetime).await?;
+                }
+                Ok(Grant { external_address: None, lifetime })
+            }
+        }
+    }
+
+    async fn external_address(&self) -> Result<Ipv4Addr> {
+        match self {
+            Client::NatPmp { gateway } => nat_pmp_external_address(*gateway).await,
+            Client::Upnp { control_url, service_type, .. } => {
+                let response = soap(control_url, service_type, "GetExternalIPAddress", &[]).await?;
+                let address = xml_text(&response, "NewExternalIPAddress").unwrap_or_default().trim();
+                address
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Router reported '{}' as its external address", address))
+            }
+            Client::Pcp { .. } => Err(anyhow::anyhow!("PCP reports the external address with each mapping")),
+        }
+    }
+
+    /// Send a request for every port, `CONCURRENT_REQUESTS` at a time, and stop sending once
+    /// `MAX_FAILURES_IN_A_ROW` requests failed in a row. Dropping the future cancels the requests.
+    async fn request_all(&self, ports: &[u16], lifetime: Duration) -> Requests {
+        let mut pending = ports.iter().copied();
+        let mut in_flight = JoinSet::new();
+        let mut requests = Requests::default();
+        let mut failures_in_a_row = 0;
+        loop {
+            while in_flight.len() < CONCURRENT_REQUESTS && failures_in_a_row < MAX_FAILURES_IN_A_ROW {
+                let Some(port) = pending.next() else {
+                    break;
+                };
+                let client = self.clone();
+                in_flight.spawn(async move { (port, client.request(port, lifetime).await) });
+            }
+            let Some(finished) = in_flight.join_next().await else {
+                break;
+            };
+            match finished {
+                Ok((port, Ok(grant))) => {
+                    failures_in_a_row = 0;
+                    requests.succeeded.push((port, grant));
+                }
+                Ok((port, Err(e))) => {
+                    failures_in_a_row += 1;
+                    if requests.failed == 0 {
+                        requests.first_error = Some(format!("port {}: {:#}", port, e));
+                    }
+                    requests.failed += 1;
+                }
+                Err(e) => {
+                    failures_in_a_row += 1;
+                    requests.failed += 1;
+                    warn!("Port mapping request failed: {}", e);
+                }
+            }
+        }
+        requests.skipped = pending.count();
+        requests
+    }
+}
+
+/// What the router granted for one port
+#[derive(Debug, Clone, Copy)]
+struct Grant {
+    /// Reported by PCP along with each mapping
+    external_address: Option<Ipv4Addr>,
+    lifetime: Duration,
+}
+
+/// Outcome of `Client::request_all`
+#[derive(Debug, Default)]
+struct Requests {
+    /// Ports in the order they succeeded
+    succeeded: Vec<(u16, Grant)>,
+    failed: usize,
+    first_error: Option<String>,
+    /// Ports not requested after too many failures in a row
+    skipped: usize,
+}
+
+impl Requests {
+    /// Shortest lifetime granted, `None` when nothing was granted
+    fn lifetime(&self) -> Option<Duration> {
+        self.succeeded.iter().map(|(_, grant)| grant.lifetime).min()
+    }
+
+    /// Log the failures of `action` (e.g. "map") out of `total` ports
+    fn warn_failures(&self, action: &str, total: usize) {
+        if let Some(error) = &self.first_error {
+            warn!("Failed to {} {}", action, error);
+        }
+        if self.failed > 0 {
+            warn!("Failed to {} {} of {} ports", action, self.failed, total);
+        }
+        if self.skipped > 0 {
+            warn!(
+                "Stopped after {} failures in a row, {} ports were not tried",
+                MAX_FAILURES_IN_A_ROW, self.skipped
+            );
+        }
+    }
+}
+
+/// Ports forwarded by the router while the server runs
+#[derive(Debug, Clone)]
+pub struct PortMapping {
+    client: Client,
+    /// Ports the router accepted
+    ports: Vec<u16>,
+    requested: usize,
+    external_address: Option<Ipv4Addr>,
+    /// Shortest lifetime the router granted, the mappings are renewed at half of it
+    lifetime: Duration,
+}
+
+impl PortMapping {
+    async fn create(client: Client, ports: &[u16]) -> Result<Self> {
+        let Some((first, rest)) = ports.split_first() else {
+            return Err(anyhow::anyhow!("No ports to map"));
+        };
+        let grant = client.request(*first, LIFETIME).await?;
+        let mut external_address = grant.external_address;
+
+        let rest = if rest.len() >= MAX_PORTS {
+            warn!(
+                "Only mapping {} of {} ports, narrow the passive port range or forward the rest by hand",
+                MAX_PORTS,
+                ports.len()
+            );
+            &rest[..MAX_PORTS - 1]
+        } else {
+            rest
+        };
+        let requests = client.request_all(rest, LIFETIME).await;
+        requests.warn_failures("map", rest.len());
+
+        let lifetime = requests.lifetime().map_or(grant.lifetime, |lifetime| lifetime.min(grant.lifetime));
+        let mut mapped = vec![*first];
+        for (port, grant) in requests.succeeded {
+            external_address = external_address.or(grant.external_address);
+            mapped.push(port);
+        }
+        mapped.sort_unstable();
+
+        if external_address.is_none() {
+            external_address = match client.external_address().await {
+                Ok(address) => Some(address),
+                Err(e) => {
+                    warn!("Failed to ask the router for its external address: {:#}", e);
+                    None
+                }
+            };
+        }
+        if let Some(address) = external_address
+            && AddressScope::of(IpAddr::V4(address)) != AddressScope::Global
+        {
+            warn!(
+                "The router's external address {} isn't public, there is probably another NAT in front of it \
+                 and clients on the internet can't reach the mapped ports",
+                address
+            );
+        }
+
+        info!(
+            "Mapped {} of {} ports via {} on {}",
+            mapped.len(),
+            ports.len(),
+            client.method(),
+            client.gateway()
+        );
+        Ok(Self {
+            client,
+            ports: mapped,
+            requested: ports.len(),
+            external_address,
+            lifetime,
+        })
+    }
+
+    /// The longest run of consecutive mapped ports within `range`, the passive ports libunftp can
+    /// hand out without sending clients to a port the router doesn't forward
+    pub fn mapped_range(&self, range: &RangeInclusive<u16>) -> Option<RangeInclusive<u16>> {
+        let mut longest: Option<RangeInclusive<u16>> = None;
+        let mut run: Option<RangeInclusive<u16>> = None;
+        for port in self.ports.iter().copied().filter(|port| range.contains(port)) {
+            run = match run {
+                Some(current) if *current.end() + 1 == port => Some(*current.start()..=port),
+                _ => Some(port..=port),
+            };
+            if let Some(current) = &run
+                && longest.as_ref().is_none_or(|longest| current.len() > longest.len())
+            {
+                longest = Some(current.clone());
+            }
+        }
+        longest
+    }
+
+    /// The router's external address if it is reachable from the internet
+    pub fn public_address(&self) -> Option<Ipv4Addr> {
+        self.external_address
+            .filter(|address| AddressScope::of(IpAddr::V4(*address)) == AddressScope::Global)
+    }
+
+    /// Description for the server info banner
+    pub fn describe(&self) -> String {
+        let mut description = format!("{} via {}", self.client.method(), self.client.gateway());
+        match self.external_address {
+            Some(address) if self.public_address().is_none() => {
+                description.push_str(&format!(", external address {} (not public)", address))
+            }
+            Some(address) => description.push_str(&format!(", external address {}", address)),
+            None => {}
+        }
+        description.push_str(&format!(", {} of {} ports mapped", self.ports.len(), self.requested));
+        description
+    }
+
+    /// Map the ports again before their lifetime runs out, at half the lifetime the router granted
+    pub fn spawn_renewal(&self) -> JoinHandle<()> {
+        let mut mapping = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(mapping.renewal_interval()).await;
+                if let Some(lifetime) = mapping.renew().await {
+                    mapping.lifetime = lifetime;
+                }
+            }
+        })
+    }
+
+    fn renewal_interval(&self) -> Duration {
+        (self.lifetime / 2).max(MIN_RENEWAL_INTERVAL)
+    }
+
+    /// Returns the shortest lifetime granted this time
+    async fn renew(&self) -> Option<Duration> {
+        let requests = self.client.request_all(&self.ports, LIFETIME).await;
+        requests.warn_failures("renew the mapping of", self.ports.len());
+        requests.lifetime()
+    }
+
+    /// Ask the router to drop the mappings, giving up after `REMOVE_TIMEOUT`
+    pub async fn remove(&self) {
+        let removed = tokio::time::timeout(REMOVE_TIMEOUT, self.client.request_all(&self.ports, Duration::ZERO)).await;
+        match removed {
+            Ok(requests) if requests.succeeded.len() == self.ports.len() => {
+                info!("Removed {} port mappings", self.ports.len());
+            }
+            Ok(requests) => {
+                requests.warn_failures("remove the mapping of", self.ports.len());
+                warn!("The router drops the remaining mappings after {}s", self.lifetime.as_secs());
+            }
+            Err(_) => warn!(
+                "The router didn't remove the port mappings within {}s, it drops them after {}s",
+                REMOVE_TIMEOUT.as_secs(),
+                self.lifetime.as_secs()
+            ),
+        }
+    }
+}
+
+/// Send a NAT-PMP/PCP request, retrying with doubling delays, until a response passes `accept`
+async fn udp_exchange(gateway: SocketAddr, request: &[u8], accept: impl Fn(&[u8]) -> bool) -> Result<Vec<u8>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(gateway).await?;
+
+    // The system reports an ICMP port unreachable as an error on the next send or receive
+    let no_server = || format!("No NAT-PMP/PCP server at {}", gateway);
+    let mut wait = UDP_RETRY;
+    let mut buf = [0u8; 1100];
+    for _ in 0..UDP_ATTEMPTS {
+        socket.send(request).await.with_context(no_server)?;
+        let deadline = Instant::now() + wait;
+        loop {
+            match tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+                Ok(Ok(len)) if accept(&buf[..len]) => return Ok(buf[..len].to_vec()),
+                Ok(Ok(_)) => continue,
+                Ok(Err(e)) => return Err(e).with_context(no_server),
+                Err(_) => break,
+            }
+        }
+        wait *= 2;
+    }
+    Err(anyhow::anyhow!("No answer from {}", gateway))
+}
+
+/// Map or, with a zero lifetime, delete a TCP port with NAT-PMP (RFC 6886). Returns the external port
+/// and the granted lifetime.
+async fn nat_pmp_map(gateway: SocketAddr, port: u16, lifetime: Duration) -> Result<(u16, Duration)> {
+    let request = nat_pmp_request(port, lifetime);
+    let response = udp_exchange(gateway, &request, |r| r.len() >= 4 && r[1] == 130).await?;
+    nat_pmp_result(&response)?;
+    if response.len() < 16 {
+        return Err(anyhow::anyhow!("Invalid NAT-PMP response from {}", gateway));
+    }
+    let granted = u32::from_be_bytes([response[12], response[13], response[14], response[15]]);
+    Ok((u16::from_be_bytes([response[10], response[11]]), Duration::from_secs(granted.into())))
+}
+
+async fn nat_pmp_external_address(gateway: SocketAddr) -> Result<Ipv4Addr> {
+    let response = udp_exchange(gateway, &[0, 0], |r| r.len() >= 4 && r[1] == 128).await?;
+    nat_pmp_result(&response)?;
+    match response.get(8..12) {
+        Some(&[a, b, c, d]) => Ok(Ipv4Addr::new(a, b, c, d)),
+        _ => Err(anyhow::anyhow!("Invalid NAT-PMP response from {}", gateway)),
+    }
+}
+
+/// NAT-PMP TCP mapping request for the same internal and external port
+fn nat_pmp_request(port: u16, lifetime: Duration) -> Vec<u8> {
+    let mut request = vec![0, 2, 0, 0];
+    request.extend_from_slice(&port.to_be_bytes());
+    // Deleting needs a suggested external port of 0
+    let external = if lifetime.is_zero() { 0 } else { port };
+    request.extend_from_slice(&external.to_be_bytes());
+    request.extend_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+    request
+}
+
+fn nat_pmp_result(response: &[u8]) -> Result<()> {
+    let reason = match u16::from_be_bytes([response[2], response[3]]) {
+        0 => return Ok(()),
+        1 => "unsupported version",
+        2 => "not authorized, port mapping may be disabled on the router",
+        3 => "network failure",
+        4 => "out of resources",
+        5 => "unsupported request",
+        _ => "unknown error",
+    };
+    Err(anyhow::anyhow!("Router answered: {}", reason))
+}
+
+/// Map or, with a zero lifetime, delete a TCP port with PCP (RFC 6887). Returns the external port and
+/// address and the granted lifetime.
+async fn pcp_map(
+    gateway: SocketAddr,
+    local: Ipv4Addr,
+    nonce: &[u8; 12],
+    port: u16,
+    lifetime: Duration,
+) -> Result<(u16, Ipv4Addr, Duration)> {
+    let request = pcp_request(local, nonce, port, lifetime);
+    let response = udp_exchange(gateway, &request, |r| {
+        // NAT-PMP-only routers answer with a version 0 error
+        r.len() >= 4 && (r[0] == 0 || (r[1] == 0x81 && r.get(24..36) == Some(nonce)))
+    })
+    .await?;
+    if response[0] == 0 {
+        return Err(anyhow::anyhow!("Router only supports NAT-PMP"));
+    }
+
+    let reason = match response[3] {
+        0 => None,
+        1 => Some("unsupported version"),
+        2 => Some("not authorized, port mapping may be disabled on the router"),
+        8 => Some("out of resources"),
+        11 => Some("can't provide the requested external port"),
+        12 => Some("address mismatch, there is another NAT between this machine and the router"),
+        _ => Some("request refused"),
+    };
+    if let Some(reason) = reason {
+        return Err(anyhow::anyhow!("Router answered: {} (PCP result {})", reason, response[3]));
+    }
+    if response.len() < 60 {
+        return Err(anyhow::anyhow!("Invalid PCP response from {}", gateway));
+    }
+
+    let mapped = u16::from_be_bytes([response[42], response[43]]);
+    let mut external = [0u8; 16];
+    external.copy_from_slice(&response[44..60]);
+    let external = Ipv6Addr::from(external)
+        .to_ipv4_mapped()
+        .ok_or_else(|| anyhow::anyhow!("Router assigned a non-IPv4 external address"))?;
+    let granted = u32::from_be_bytes([response[4], response[5], response[6], response[7]]);
+    Ok((mapped, external, Duration::from_secs(granted.into())))
+}
+
+/// PCP MAP request for TCP `port`, suggesting the same external port
+fn pcp_request(local: Ipv4Addr, nonce: &[u8; 12], port: u16, lifetime: Duration) -> Vec<u8> {
+    // Common header: version 2, MAP request, client address as IPv4-mapped IPv6
+    let mut request = vec![2, 1, 0, 0];
+    request.extend_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+    request.extend_from_slice(&local.to_ipv6_mapped().octets());
+    // MAP payload: nonce, TCP, suggested external port and address (any IPv4 address)
+    request.extend_from_slice(nonce);
+    request.extend_from_slice(&[6, 0, 0, 0]);
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+    request
+}
+
+async fn upnp_add(control_url: &str, service_type: &str, local: Ipv4Addr, port: u16, lifetime: Duration) -> Result<()> {
+    let add = |lease: u64| {
+        let args = [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", port.to_string()),
+            ("NewProtocol", "TCP".to_string()),
+            ("NewInternalPort", port.to_string()),
+            ("NewInternalClient", local.to_string()),
+            ("NewEnabled", "1".to_string()),
+            ("NewPortMappingDescription", format!("SixFTP {}", port)),
+            ("NewLeaseDuration", lease.to_string()),
+        ];
+        async move { soap(control_url, service_type, "AddPortMapping", &args).await }
+    };
+    match add(lifetime.as_secs()).await {
+        Err(e) if e.downcast_ref::<UpnpError>().is_some_and(|e| e.code == ONLY_PERMANENT_LEASES) => {
+            add(0).await.map(|_| ())
+        }
+        result => result.map(|_| ()),
+    }
+}
+
+async fn upnp_delete(control_url: &str, service_type: &str, port: u16) -> Result<()> {
+    let args = [
+        ("NewRemoteHost", String::new()),
+        ("NewExternalPort", port.to_string()),
+        ("NewProtocol", "TCP".to_string()),
+    ];
+    soap(control_url, service_type, "DeletePortMapping", &args).await.map(|_| ())
+}
+
+/// Find a UPnP Internet Gateway Device with SSDP and return its description URL
+async fn discover_upnp(gateway: Option<IpAddr>) -> Result<String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    for version in 1..=2 {
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\
+             ST: urn:schemas-upnp-org:device:InternetGatewayDevice:{}\r\n\r\n",
+            SSDP_ADDR, version
+        );
+        socket.send_to(search.as_bytes(), SSDP_ADDR).await?;
+    }
+
+    let deadline = Instant::now() + SSDP_WAIT;
+    let mut buf = [0u8; 2048];
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, from) = received?;
+        if gateway.is_some_and(|gateway| gateway != from.ip()) {
+            continue;
+        }
+        let response = String::from_utf8_lossy(&buf[..len]);
+        let location = response.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim().eq_ignore_ascii_case("location").then(|| value.trim().to_string())
+        });
+        if let Some(location) = location {
+            return Ok(location);
+        }
+    }
+    Err(anyhow::anyhow!("No UPnP gateway answered within {}s", SSDP_WAIT.as_secs()))
+}
+
+/// Read a device description and find its WAN connection service
+async fn upnp_client(location: &str) -> Result<Client> {
+    let (status, description) = http_request(location, "GET", &[], "")
+        .await
+        .with_context(|| format!("Failed to read the UPnP description {}", location))?;
+    if status != 200 {
+        return Err(anyhow::anyhow!("UPnP description {} answered HTTP {}", location, status));
+    }
+
+    let services: Vec<(&str, &str)> = description
+        .split("<service>")
+        .skip(1)
+        .filter_map(|service| Some((xml_text(service, "serviceType")?.trim(), xml_text(service, "controlURL")?.trim())))
+        .collect();
+    let (service_type, control_url) = ["WANIPConnection:", "WANPPPConnection:"]
+        .iter()
+        .find_map(|wanted| services.iter().find(|(service_type, _)| service_type.contains(wanted)))
+        .ok_or_else(|| anyhow::anyhow!("{} is not an Internet gateway", location))?;
+
+    let base = xml_text(&description, "URLBase").map(str::trim).filter(|base| !base.is_empty()).unwrap_or(location);
+    let control_url = join_url(base, control_url)?;
+    let (authority, _) = split_url(&control_url)?;
+    let gateway = resolve_ipv4(authority).await?;
+    Ok(Client::Upnp {
+        local: local_address_towards(gateway.ip()).await?,
+        control_url,
+        service_type: service_type.to_string(),
+    })
+}
+
+/// Call a UPnP action and return the response body
+async fn soap(control_url: &str, service_type: &str, action: &str, args: &[(&str, String)]) -> Result<String> {
+    let body = soap_envelope(service_type, action, args);
+    let soap_action = format!("\"{}#{}\"", service_type, action);
+    let headers = [("Content-Type", "text/xml; charset=\"utf-8\""), ("SOAPAction", soap_action.as_str())];
+
+    let (status, response) = http_request(control_url, "POST", &headers, &body).await?;
+    match status {
+        200 => Ok(response),
+        _ => match xml_text(&response, "errorCode").and_then(|code| code.trim().parse().ok()) {
+            Some(code) => Err(UpnpError {
+                action: action.to_string(),
+                code,
+                description: xml_text(&response, "errorDescription").unwrap_or_default().trim().to_string(),
+            }
+            .into()),
+            None => Err(anyhow::anyhow!("Router answered HTTP {} to {}", status, action)),
+        },
+    }
+}
+
+fn soap_envelope(service_type: &str, action: &str, args: &[(&str, String)]) -> String {
+    let args: String = args.iter().map(|(name, value)| format!("<{0}>{1}</{0}>", name, value)).collect();
+    format!(
+        "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>\
+         <u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>\r\n",
+        action, service_type, args
+    )
+}
+
+/// Error a UPnP router reported for an action
+#[derive(Debug)]
+struct UpnpError {
+    action: String,
+    code: u32,
+    description: String,
+}
+
+impl fmt::Display for UpnpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Router refused {}: UPnP error {} ({})", self.action, self.code, self.description)
+    }
+}
+
+impl std::error::Error for UpnpError {}
+
+/// Minimal HTTP/1.0 client, returning the status code and the body
+async fn http_request(url: &str, method: &str, headers: &[(&str, &str)], body: &str) -> Result<(u16, String)> {
+    let (authority, path) = split_url(url)?;
+    let mut request = format!("{} {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: SixFTP\r\nConnection: close\r\n", method, path, authority);
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+
+    let addr = resolve_ipv4(authority).await?;
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.take(1024 * 1024).read_to_end(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    };
+    let response = tokio::time::timeout(HTTP_TIMEOUT, exchange)
+        .await
+        .map_err(|_| anyhow::anyhow!("No answer from {} within {}s", addr, HTTP_TIMEOUT.as_secs()))?
+        .with_context(|| format!("Failed to talk to {}", addr))?;
+
+    // Decode only after dechunking, chunk sizes count bytes and may split a UTF-8 sequence
+    let head_end = find_bytes(&response, b"\r\n\r\n")
+        .ok_or_else(|| anyhow::anyhow!("Invalid HTTP response from {}", addr))?;
+    let head = String::from_utf8_lossy(&response[..head_end]);
+    let body = &response[head_end + 4..];
+    let status = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("Invalid HTTP response from {}", addr))?;
+    // Some routers answer HTTP/1.0 requests with chunked HTTP/1.1 responses anyway
+    let chunked = head
+        .lines()
+        .any(|line| line.to_ascii_lowercase().replace(' ', "") == "transfer-encoding:chunked");
+    let body = if chunked { dechunk(body) } else { body.to_vec() };
+    Ok((status, String::from_utf8_lossy(&body).into_owned()))
+}
+
+fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::new();
+    while let Some(line_end) = find_bytes(body, b"\r\n") {
+        let size = std::str::from_utf8(&body[..line_end])
+            .ok()
+            .and_then(|line| usize::from_str_radix(line.split(';').next().unwrap_or_default().trim(), 16).ok())
+            .unwrap_or(0);
+        let rest = &body[line_end + 2..];
+        if size == 0 || rest.len() < size {
+            break;
+        }
+        decoded.extend_from_slice(&rest[..size]);
+        body = rest[size..].strip_prefix(b"\r\n").unwrap_or(&rest[size..]);
+    }
+    decoded
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Split `http://host:port/path` into `host:port` and `/path`
+fn split_url(url: &str) -> Result<(&str, &str)> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| anyhow::anyhow!("Unsupported URL '{}', only http:// is supported", url))?;
+    Ok(match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    })
+}
+
+/// Resolve a control URL from a device description against the description's URL
+fn join_url(base: &str, url: &str) -> Result<String> {
+    if url.starts_with("http://") {
+        return Ok(url.to_string());
+    }
+    let (authority, path) = split_url(base)?;
+    if url.starts_with('/') {
+        return Ok(format!("http://{}{}", authority, url));
+    }
+    let directory = &path[..path.rfind('/').map_or(0, |index| index + 1)];
+    Ok(format!("http://{}{}{}", authority, directory, url))
+}
+
+async fn resolve_ipv4(authority: &str) -> Result<SocketAddr> {
+    let authority = if authority.contains(':') { authority.to_string() } else { format!("{}:80", authority) };
+    tokio::net::lookup_host(&authority)
+        .await
+        .with_context(|| format!("Failed to resolve '{}'", authority))?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| anyhow::anyhow!("'{}' has no IPv4 address", authority))
+}
+
+/// Text of the first `<tag>` element, good enough for UPnP's flat XML
+fn xml_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = xml[start..].find(&format!("</{}>", tag))?;
+    Some(&xml[start..start + end])
+}
+
+/// The local address the system uses to reach `target`; connecting a UDP socket sends nothing
+async fn local_address_towards(target: IpAddr) -> Result<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect((target, NAT_PMP_PORT)).await?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(local) => Ok(local),
+        IpAddr::V6(_) => Err(anyhow::anyhow!("No IPv4 route to {}", target)),
+    }
+}
+
+/// The IPv4 default gateway from the routing table on Linux, otherwise the first address of the
+/// local /24 network, which is where most home routers are
+fn default_gateway() -> Result<Ipv4Addr> {
+    #[cfg(target_os = "linux")]
+    if let Ok(routes) = std::fs::read_to_string("/proc/net/route") {
+        // Columns: interface, destination, gateway, ...; addresses are hex in host byte order
+        let gateway = routes.lines().skip(1).find_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.get(1) != Some(&"00000000") {
+                return None;
+            }
+            u32::from_str_radix(fields.get(2)?, 16).ok()
+        });
+        if let Some(gateway) = gateway.filter(|gateway| *gateway != 0) {
+            return Ok(Ipv4Addr::from(gateway.to_ne_bytes()));
+        }
+    }
+
+    // Connecting a UDP socket picks the interface of the default route without sending anything
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket
+        .connect((Ipv4Addr::new(192, 0, 2, 1), NAT_PMP_PORT))
+        .context("No IPv4 default route, set the gateway address")?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(local) if AddressScope::of(IpAddr::V4(local)) == AddressScope::Private => {
+            let [a, b, c, _] = local.octets();
+            let gateway = Ipv4Addr::new(a, b, c, 1);
+            info!("Assuming the router is at {}, set the gateway address if it isn't", gateway);
+            Ok(gateway)
+        }
+        local => Err(anyhow::anyhow!("{} is not behind a NAT router, set the gateway address if it is", local)),
+    }
+}
+
+fn random_nonce() -> [u8; 12] {
+    // RandomState is seeded randomly per process, which is plenty for telling our mappings apart
+    let mut nonce = [0u8; 12];
+    for chunk in nonce.chunks_mut(6) {
+        chunk.copy_from_slice(&RandomState::new().build_hasher().finish().to_be_bytes()[..6]);
+    }
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    const EXTERNAL: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 20);
+
+    /// Answer NAT-PMP/PCP requests on a loopback port with `respond`, recording every request
+    async fn udp_gateway(
+        respond: impl Fn(&[u8]) -> Option<Vec<u8>> + Send + 'static,
+    ) -> (SocketAddr, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let gateway = socket.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1100];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                recorded.lock().unwrap().push(buf[..len].to_vec());
+                if let Some(response) = respond(&buf[..len]) {
+                    socket.send_to(&response, from).await.unwrap();
+                }
+            }
+        });
+        (gateway, requests)
+    }
+
+    /// NAT-PMP answer to `request` with result `result`, mapping to `external_port` or the requested port
+    fn nat_pmp_response(request: &[u8], external_port: Option<u16>, result: u16) -> Vec<u8> {
+        match request[1] {
+            0 => {
+                let mut response = vec![0, 128, 0, 0, 0, 0, 0, 1];
+                response.extend_from_slice(&EXTERNAL.octets());
+                response
+            }
+            _ => {
+                let mut response = vec![0, 130];
+                response.extend_from_slice(&result.to_be_bytes());
+                response.extend_from_slice(&[0, 0, 0, 1]);
+                response.extend_from_slice(&request[4..6]);
+                response.extend_from_slice(&external_port.map_or([request[6], request[7]], u16::to_be_bytes));
+                response.extend_from_slice(&request[8..12]);
+                response
+            }
+        }
+    }
+
+    fn requested_port(request: &[u8]) -> u16 {
+        u16::from_be_bytes([request[4], request[5]])
+    }
+
+    fn requested_lifetime(request: &[u8]) -> u32 {
+        u32::from_be_bytes([request[8], request[9], request[10], request[11]])
+    }
+
+    #[test]
+    fn encodes_nat_pmp_requests() {
+        assert_eq!(
+            nat_pmp_request(2121, LIFETIME),
+            [0, 2, 0, 0, 0x08, 0x49, 0x08, 0x49, 0, 0, 0x1c, 0x20]
+        );
+        // Deleting suggests external port 0 with a zero lifetime
+        assert_eq!(nat_pmp_request(2121, Duration::ZERO), [0, 2, 0, 0, 0x08, 0x49, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn encodes_pcp_requests() {
+        let nonce = [7u8; 12];
+        let request = pcp_request(Ipv4Addr::new(192, 168, 1, 20), &nonce, 2121, LIFETIME);
+        assert_eq!(request.len(), 60);
+        assert_eq!(request[..8], [2, 1, 0, 0, 0, 0, 0x1c, 0x20]);
+        assert_eq!(request[8..24], Ipv4Addr::new(192, 168, 1, 20).to_ipv6_mapped().octets());
+        assert_eq!(request[24..36], nonce);
+        assert_eq!(request[36..40], [6, 0, 0, 0]);
+        assert_eq!(request[40..44], [0x08, 0x49, 0x08, 0x49]);
+        assert_eq!(request[44..60], Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+    }
+
+    #[test]
+    fn encodes_soap_envelopes() {
+        let envelope = soap_envelope(
+            "urn:schemas-upnp-org:service:WANIPConnection:1",
+            "DeletePortMapping",
+            &[("NewExternalPort", "2121".to_string()), ("NewProtocol", "TCP".to_string())],
+        );
+        assert!(envelope.contains(
+            "<u:DeletePortMapping xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">\
+             <NewExternalPort>2121</NewExternalPort><NewProtocol>TCP</NewProtocol></u:DeletePortMapping>"
+        ));
+        assert_eq!(xml_text(&envelope, "NewExternalPort"), Some("2121"));
+    }
+
+    #[test]
+    fn dechunks_bytes_before_decoding() {
+        // "é" is split across two chunks, slicing a decoded string here used to panic
+        let body = b"1\r\n\xc3\r\n2;name=value\r\n\xa9!\r\n0\r\n\r\n";
+        assert_eq!(String::from_utf8_lossy(&dechunk(body)), "é!");
+        // A truncated chunk ends the body
+        assert_eq!(dechunk(b"3\r\nabc\r\n10\r\nshort"), b"abc");
+        assert_eq!(dechunk(b"nonsense\r\n"), b"");
+        assert_eq!(dechunk(b""), b"");
+    }
+
+    #[test]
+    fn joins_control_urls() {
+        let base = "http://192.168.1.1:5000/rootDesc.xml";
+        assert_eq!(join_url(base, "/ctl/IPConn").unwrap(), "http://192.168.1.1:5000/ctl/IPConn");
+        assert_eq!(join_url(base, "ctl/IPConn").unwrap(), "http://192.168.1.1:5000/ctl/IPConn");
+        assert_eq!(
+            join_url("http://192.168.1.1:5000/desc/root.xml", "ctl").unwrap(),
+            "http://192.168.1.1:5000/desc/ctl"
+        );
+        assert_eq!(join_url("http://192.168.1.1", "ctl").unwrap(), "http://192.168.1.1/ctl");
+        assert_eq!(join_url(base, "http://10.0.0.1/ctl").unwrap(), "http://10.0.0.1/ctl");
+        assert!(join_url("https://192.168.1.1/", "ctl").is_err());
+    }
+
+    #[test]
+    fn reads_xml_text() {
+        let xml = "<root><URLBase> http://a/ </URLBase><empty></empty><a><b>1</b></a><b>2</b></root>";
+        assert_eq!(xml_text(xml, "URLBase"), Some(" http://a/ "));
+        assert_eq!(xml_text(xml, "empty"), Some(""));
+        assert_eq!(xml_text(xml, "b"), Some("1"));
+        assert_eq!(xml_text(xml, "missing"), None);
+        assert_eq!(xml_text("<open>never closed", "open"), None);
+    }
+
+    #[tokio::test]
+    async fn maps_with_nat_pmp() {
+        // The router refuses one passive port, which is left out
+        let (gateway, requests) = udp_gateway(|request| {
+            let refused = request[1] == 2 && requested_port(request) == 50001;
+            Some(nat_pmp_response(request, None, if refused { 2 } else { 0 }))
+        })
+        .await;
+
+        let mapping = PortMapping::create(Client::NatPmp { gateway }, &[2121, 50000, 50001, 50002]).await.unwrap();
+        assert_eq!(mapping.ports, [2121, 50000, 50002]);
+        assert_eq!(mapping.external_address, Some(EXTERNAL));
+        assert_eq!(mapping.lifetime, LIFETIME);
+        assert!(mapping.describe().contains("3 of 4 ports mapped"));
+        assert_eq!(mapping.mapped_range(&(50000..=50002)), Some(50000..=50000));
+
+        requests.lock().unwrap().clear();
+        mapping.remove().await;
+        let requests = requests.lock().unwrap();
+        assert!(requests.iter().all(|request| request[6..8] == [0, 0] && requested_lifetime(request) == 0));
+        let mut deleted: Vec<u16> = requests.iter().map(|request| requested_port(request)).collect();
+        deleted.sort_unstable();
+        assert_eq!(deleted, [2121, 50000, 50002]);
+    }
+
+    #[tokio::test]
+    async fn renews_at_half_the_granted_lifetime() {
+        // The router grants ten minutes for the passive port and an hour for the control port
+        let (gateway, _) = udp_gateway(|request| {
+            let mut response = nat_pmp_response(request, None, 0);
+            if request[1] == 2 {
+                let granted: u32 = if requested_port(request) == 2121 { 3600 } else { 600 };
+                response[12..16].copy_from_slice(&granted.to_be_bytes());
+            }
+            Some(response)
+        })
+        .await;
+
+        let mapping = PortMapping::create(Client::NatPmp { gateway }, &[2121, 50000]).await.unwrap();
+        assert_eq!(mapping.lifetime, Duration::from_secs(600));
+        assert_eq!(mapping.renewal_interval(), Duration::from_secs(300));
+        assert_eq!(mapping.renew().await, Some(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn limits_passive_ports_to_the_longest_mapped_run() {
+        let mapping = |ports: Vec<u16>| PortMapping {
+            client: Client::NatPmp { gateway: SocketAddr::from((Ipv4Addr::LOCALHOST, NAT_PMP_PORT)) },
+            requested: ports.len(),
+            ports,
+            external_address: None,
+            lifetime: Duration::ZERO,
+        };
+        let range = 50000..=50009;
+        assert_eq!(mapping((50000..=50009).collect()).mapped_range(&range), Some(range.clone()));
+        // The control port is mapped as well but isn't a passive port
+        let ports = vec![2121, 50000, 50001, 50003, 50004, 50005, 50008];
+        assert_eq!(mapping(ports).mapped_range(&range), Some(50003..=50005));
+        assert_eq!(mapping(vec![2121]).mapped_range(&range), None);
+        assert_eq!(mapping(vec![]).renewal_interval(), MIN_RENEWAL_INTERVAL);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_different_external_port() {
+        let (gateway, requests) = udp_gateway(|request| {
+            let external = if requested_lifetime(request) == 0 { 0 } else { requested_port(request) + 1 };
+            Some(nat_pmp_response(request, Some(external), 0))
+        })
+        .await;
+
+        let error = Client::NatPmp { gateway }.request(2121, LIFETIME).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("external port 2122"));
+        // The unusable mapping is deleted again
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requested_lifetime(&requests[1]), 0);
+    }
+
+    #[tokio::test]
+    async fn stops_after_failures_in_a_row() {
+        let (gateway, requests) = udp_gateway(|request| {
+            let refused = request[1] == 2 && requested_port(request) != 2121;
+            Some(nat_pmp_response(request, None, if refused { 3 } else { 0 }))
+        })
+        .await;
+
+        let ports: Vec<u16> = std::iter::once(2121).chain(50000..50100).collect();
+        let mapping = PortMapping::create(Client::NatPmp { gateway }, &ports).await.unwrap();
+        assert_eq!(mapping.ports, [2121]);
+        let mappings = requests.lock().unwrap().iter().filter(|request| request[1] == 2).count();
+        assert!(mappings <= 1 + CONCURRENT_REQUESTS + MAX_FAILURES_IN_A_ROW, "{} requests sent", mappings);
+    }
+
+    #[tokio::test]
+    async fn maps_with_pcp() {
+        let (gateway, requests) = udp_gateway(|request| {
+            // Header with the granted lifetime, then the MAP payload with the assigned port and address
+            let mut response = vec![2, 0x81, 0, 0];
+            response.extend_from_slice(&1200u32.to_be_bytes());
+            response.extend_from_slice(&[0; 16]);
+            response.extend_from_slice(&request[24..42]);
+            response.extend_from_slice(&request[40..42]);
+            response.extend_from_slice(&EXTERNAL.to_ipv6_mapped().octets());
+            Some(response)
+        })
+        .await;
+
+        let nonce = random_nonce();
+        let client = Client::Pcp { gateway, local: Ipv4Addr::LOCALHOST, nonce };
+        let mapping = PortMapping::create(client, &[2121, 50000]).await.unwrap();
+        assert_eq!(mapping.ports, [2121, 50000]);
+        assert_eq!(mapping.external_address, Some(EXTERNAL));
+        assert_eq!(mapping.lifetime, Duration::from_secs(1200));
+        let requests = requests.lock().unwrap();
+        assert!(requests.iter().all(|request| request[24..36] == nonce));
+    }
+
+    #[tokio::test]
+    async fn pcp_reports_nat_pmp_only_routers() {
+        let (gateway, _) = udp_gateway(|_| Some(vec![0, 129, 0, 1, 0, 0, 0, 0])).await;
+        let client = Client::Pcp { gateway, local: Ipv4Addr::LOCALHOST, nonce: random_nonce() };
+        let error = client.request(2121, LIFETIME).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("only supports NAT-PMP"));
+    }
+
+    /// Answer HTTP requests on a loopback port with `respond`, recording every request
+    async fn http_gateway(
+        respond: impl Fn(&str) -> Vec<u8> + Send + 'static,
+    ) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                loop {
+                    let len = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..len]);
+                    let Some(head_end) = find_bytes(&request, b"\r\n\r\n") else {
+                        continue;
+                    };
+                    let head = String::from_utf8_lossy(&request[..head_end]).to_ascii_lowercase();
+                    let length = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length:"))
+                        .map_or(0, |length| length.trim().parse().unwrap());
+                    if len == 0 || request.len() >= head_end + 4 + length {
+                        break;
+                    }
+                }
+                let request = String::from_utf8(request).unwrap();
+                let response = respond(&request);
+                recorded.lock().unwrap().push(request);
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+        (address, requests)
+    }
+
+    fn soap_response(action: &str, body: &str) -> Vec<u8> {
+        let envelope = soap_envelope("urn:schemas-upnp-org:service:WANIPConnection:1", &format!("{}Response", action), &[]);
+        let envelope = envelope.replace("></u:", &format!(">{}</u:", body));
+        format!("HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\n\r\n{}", envelope).into_bytes()
+    }
+
+    #[tokio::test]
+    async fn maps_with_upnp() {
+        // The description comes chunked, with a multi-byte name split across chunks and a relative
+        // control URL; the router only accepts permanent leases
+        let description = "<?xml version=\"1.0\"?><root><device><friendlyName>Routeur A café</friendlyName>\
+            <serviceList><service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+            <controlURL>/l3f</controlURL></service><service>\
+            <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+            <controlURL>ctl/IPConn</controlURL></service></serviceList></device></root>";
+        let mut chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        for chunk in description.as_bytes().chunks(7) {
+            chunked.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+            chunked.extend_from_slice(chunk);
+            chunked.extend_from_slice(b"\r\n");
+        }
+        chunked.extend_from_slice(b"0\r\n\r\n");
+        let (address, requests) = http_gateway(move |request| {
+            if request.starts_with("GET /desc/root.xml ") {
+                chunked.clone()
+            } else if request.contains("#AddPortMapping") && !request.contains("<NewLeaseDuration>0<") {
+                "HTTP/1.1 500 Internal Server Error\r\n\r\n<s:Envelope><s:Body><s:Fault><detail><UPnPError>\
+                 <errorCode>725</errorCode><errorDescription>OnlyPermanentLeasesSupported</errorDescription>\
+                 </UPnPError></detail></s:Fault></s:Body></s:Envelope>"
+                    .as_bytes()
+                    .to_vec()
+            } else if request.contains("#GetExternalIPAddress") {
+                soap_response("GetExternalIPAddress", &format!("<NewExternalIPAddress>{}</NewExternalIPAddress>", EXTERNAL))
+            } else {
+                soap_response("AddPortMapping", "")
+            }
+        })
+        .await;
+
+        let location = format!("http://{}/desc/root.xml", address);
+        let (status, body) = http_request(&location, "GET", &[], "").await.unwrap();
+        assert_eq!(status, 200);
+        assert!(body.contains("<friendlyName>Routeur A café</friendlyName>"));
+        let client = upnp_client(&location).await.unwrap();
+        let Client::Upnp { control_url, service_type, local } = &client else {
+            panic!("expected a UPnP client, got {:?}", client);
+        };
+        assert_eq!(control_url, &format!("http://{}/desc/ctl/IPConn", address));
+        assert_eq!(service_type, "urn:schemas-upnp-org:service:WANIPConnection:1");
+        assert_eq!(local, &Ipv4Addr::LOCALHOST);
+
+        let mapping = PortMapping::create(client, &[2121]).await.unwrap();
+        assert_eq!(mapping.ports, [2121]);
+        assert_eq!(mapping.external_address, Some(EXTERNAL));
+        mapping.remove().await;
+
+        let requests = requests.lock().unwrap();
+        let actions: Vec<&str> = requests
+            .iter()
+            .map(|request| {
+                let start = request.find("SOAPAction: \"").map_or(0, |start| start + 13);
+                let end = request[start..].find('"').map_or(start, |end| start + end);
+                request[start..end].rsplit('#').next().unwrap_or_default()
+            })
+            .collect();
+        assert_eq!(actions[2..], ["AddPortMapping", "AddPortMapping", "GetExternalIPAddress", "DeletePortMapping"]);
+        assert!(requests[3].contains("<NewLeaseDuration>0</NewLeaseDuration>"));
+        assert!(requests[3].contains("<NewInternalClient>127.0.0.1</NewInternalClient>"));
+        assert!(requests[5].contains("<NewExternalPort>2121</NewExternalPort>"));
+    }
+}
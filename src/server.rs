@@ -0,0 +1,933 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use libunftp::notification::{EventMeta, PresenceEvent, PresenceListener};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use libunftp::options::PassiveHost;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info, warn};
+use crate::audit::AuditLog;
+use crate::auth::{AnonymousAccess, ConnectionAuthenticator, SixFtpAuthenticator};
+use crate::bans::LoginProtection;
+use crate::ip_rules::IpRules;
+use crate::network_info::{self, AddressScope, ServerInfo};
+use crate::passive_host::PassiveHostConfig;
+use crate::port_mapping::{PortMapping, PortMappingConfig, PortMappingMethod};
+use crate::sessions::{Connection, ConnectionLimits, SessionRegistry};
+use crate::storage::{self, AccessMode};
+use crate::throttle::{RateLimits, Throttle};
+use crate::tls::{self, TlsConfig};
+use crate::users::{self, UserAccount};
+
+const GREETING: &str = "Welcome to SixFTP Server";
+
+/// How long an implicit FTPS client may take to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most passive ports probed at startup, wider ranges are sampled
+const MAX_PORT_PROBES: usize = 256;
+
+/// Everything needed to start the FTP listeners, shared by CLI and GUI mode
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub directory: PathBuf,
+    pub port: u16,
+    pub bind_addr: IpAddr,
+    pub pasv_range: RangeInclusive<u16>,
+    /// Address advertised in PASV replies
+    pub passive_host: PassiveHostConfig,
+    /// Ask the router to forward the control port and the passive range
+    pub port_mapping: PortMappingConfig,
+    pub username: String,
+    pub password: String,
+    /// Accounts from a users file; when non-empty they replace `username`/`password`
+    pub accounts: Vec<UserAccount>,
+    pub anonymous: Option<AnonymousAccess>,
+    pub access_mode: AccessMode,
+    pub tls: Option<TlsConfig>,
+    /// How long active sessions may keep transferring after a stop is requested
+    pub shutdown_timeout: Duration,
+    /// JSON lines file recording logins, logouts and file operations
+    pub audit_log: Option<PathBuf>,
+    pub rate_limits: RateLimits,
+    pub connection_limits: ConnectionLimits,
+    pub login_protection: LoginProtection,
+    /// Client addresses allowed to connect
+    pub ip_rules: IpRules,
+}
+
+/// State shared by the listeners and connections of one server run
+struct Services {
+    config: ServerConfig,
+    authenticator: Arc<SixFtpAuthenticator>,
+    sessions: SessionRegistry,
+    audit: Option<AuditLog>,
+    throttle: Throttle,
+    passive_host: PassiveHost,
+}
+
+/// Fans libunftp's presence events on one control connection out to the session registry and the
+/// audit log
+#[derive(Debug)]
+struct PresenceListeners {
+    sessions: SessionRegistry,
+    audit: Option<AuditLog>,
+    connection: u64,
+}
+
+#[async_trait]
+impl PresenceListener for PresenceListeners {
+    async fn receive_presence_event(&self, event: PresenceEvent, meta: EventMeta) {
+        // The registry links the trace id on login, which the audit log uses to find the remote address
+        self.sessions.record_presence(&event, &meta, self.connection);
+        if let Some(audit) = &self.audit {
+            audit.receive_presence_event(event, meta).await;
+        }
+    }
+}
+
+impl ServerConfig {
+    fn authenticator(&self) -> SixFtpAuthenticator {
+        let authenticator = if self.accounts.is_empty() {
+            SixFtpAuthenticator::new(&self.username, &self.password)
+        } else {
+            SixFtpAuthenticator::from_accounts(&self.accounts)
+        };
+        authenticator.with_anonymous(self.anonymous.clone())
+    }
+
+    /// Addresses to listen on; an unspecified address means both IPv6 and IPv4 (in that order, see
+    /// `start`), or in LAN only mode every loopback, private and unique local address of this machine
+    fn listen_addresses(&self) -> Result<Vec<IpAddr>> {
+        if !self.bind_addr.is_unspecified() {
+            if self.ip_rules.lan_only && !AddressScope::of(self.bind_addr).is_lan() {
+                return Err(anyhow::anyhow!(
+                    "LAN only mode can't listen on {}, it is reachable from outside the local network",
+                    self.bind_addr
+                ));
+            }
+            return Ok(vec![self.bind_addr]);
+        }
+
+        if !self.ip_rules.lan_only {
+            return Ok(vec![IpAddr::V6(Ipv6Addr::UNSPECIFIED), IpAddr::V4(Ipv4Addr::UNSPECIFIED)]);
+        }
+
+        // Link-local addresses would need an interface scope to bind, so only loopback and private ones
+        let network_ips = network_info::get_network_ips()?;
+        let mut addresses: Vec<IpAddr> = Vec::new();
+        for ip in network_ips.ipv4.into_iter().map(IpAddr::V4).chain(network_ips.ipv6.into_iter().map(IpAddr::V6)) {
+            if matches!(AddressScope::of(ip), AddressScope::Loopback | AddressScope::Private) && !addresses.contains(&ip) {
+                addresses.push(ip);
+            }
+        }
+        Ok(addresses)
+    }
+}
+
+impl Services {
+    /// libunftp server for one control connection, `implicit` for one from the implicit FTPS listener
+    fn build_server(
+        &self,
+        connection: u64,
+        remote: IpAddr,
+        implicit: bool,
+    ) -> Result<libunftp::Server<storage::SixFs, users::FtpUser>> {
+        let config = &self.config;
+        let fs = storage::SixFs::new(
+            config.directory.clone(),
+            config.access_mode,
+            self.sessions.clone(),
+            self.throttle.clone(),
+        )
+        .map_err(|e| anyhow::anyhow!("Directory '{}' is not accessible: {}", config.directory.display(), e))?;
+        let builder = storage::server_builder(
+            fs,
+            Arc::new(ConnectionAuthenticator::new(self.authenticator.clone(), connection, remote)),
+        );
+        let builder = match &self.audit {
+            Some(audit) => builder.notify_data(audit.clone()),
+            None => builder,
+        };
+        tls::configure(builder, config.tls.as_ref(), implicit)
+            .passive_ports(config.pasv_range.clone())
+            .passive_host(self.passive_host.clone())
+            .greeting(GREETING)
+            .notify_presence(PresenceListeners {
+                sessions: self.sessions.clone(),
+                audit: self.audit.clone(),
+                connection,
+            })
+            .build()
+            .map_err(|e| anyhow::anyhow!("Failed to configure FTP server: {}", e))
+    }
+
+    /// Admit an accepted control connection and serve it until either side closes it. `tls` is set
+    /// for connections to the implicit FTPS listener, which start with a TLS handshake.
+    async fn serve(&self, stream: TcpStream, peer: SocketAddr, tls: Option<TlsAcceptor>) {
+        let remote = peer.ip().to_canonical();
+        let local = match stream.local_addr() {
+            Ok(local) => local,
+            Err(e) => {
+                warn!("Dropped the connection from {}: {}", remote, e);
+                return;
+            }
+        };
+        let admitted = self.admit(remote);
+
+        let Some(acceptor) = tls else {
+            return self.serve_client(stream, local, remote, admitted, false).await;
+        };
+        match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => self.serve_client(stream, local, remote, admitted, true).await,
+            Ok(Err(e)) => info!("TLS handshake with {} failed: {}", remote, e),
+            Err(_) => info!("TLS handshake with {} timed out", remote),
+        }
+    }
+
+    /// Check a new connection against the address rules, bans and connection limits. Refused
+    /// connections get the reason to send the client.
+    fn admit(&self, remote: IpAddr) -> Result<Connection, &'static str> {
+        if !self.config.ip_rules.permits(remote) {
+            warn!("Refused connection from {}: address not allowed", remote);
+            return Err("Connections from your address are not allowed");
+        }
+        if self.sessions.bans().is_banned(remote) {
+            warn!("Refused connection from banned address {}", remote);
+            return Err("Your address is banned, try again later");
+        }
+        self.sessions.connect(remote).map_err(|e| {
+            warn!("Refused connection from {}: {}", remote, e);
+            "Too many connections, try again later"
+        })
+    }
+
+    async fn serve_client<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        client: S,
+        local: SocketAddr,
+        remote: IpAddr,
+        admitted: Result<Connection, &'static str>,
+        implicit: bool,
+    ) {
+        let connection = match admitted {
+            Ok(connection) => connection,
+            Err(reason) => return refuse(client, reason).await,
+        };
+        // The served directory may have gone away since the server started
+        let server = match self.build_server(connection.id(), remote, implicit) {
+            Ok(server) => server,
+            Err(e) => {
+                error!("Can't serve {}: {:#}", remote, e);
+                return refuse(client, "Service not available, try again later").await;
+            }
+        };
+        if let Err(e) = self.relay(client, local, &connection, server).await {
+            warn!("Control connection from {} failed: {:#}", remote, e);
+        }
+    }
+
+    /// Relay the control connection to its own libunftp server. libunftp takes the connection as a
+    /// `TcpStream` it owns, which would leave the server unable to tell when it ends or to close it,
+    /// and implicit FTPS needs the TLS layer in between, so it gets one end of a local connection
+    /// instead. That connection is made on `local`, the address the client connected to, where
+    /// libunftp also binds the passive ports and which PASV advertises.
+    async fn relay<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        client: S,
+        mut local: SocketAddr,
+        connection: &Connection,
+        server: libunftp::Server<storage::SixFs, users::FtpUser>,
+    ) -> Result<()> {
+        if let IpAddr::V6(ip) = local.ip()
+            && let Some(ip) = ip.to_ipv4_mapped()
+        {
+            local = SocketAddr::new(IpAddr::V4(ip), 0);
+        }
+        local.set_port(0);
+        let (relay, inner) = local_pair(local).await?;
+
+        let relayed = async move {
+            let (mut client, mut relay) = (client, relay);
+            tokio::select! {
+                // Either side closing ends the relay, and dropping it closes the other side
+                _ = tokio::io::copy_bidirectional(&mut client, &mut relay) => {}
+                _ = connection.closed() => {}
+            }
+        };
+        // Whether `service` returns right away or when the session ends, the relay runs alongside
+        let (served, ()) = tokio::join!(server.service(inner), relayed);
+        served.map_err(|e| anyhow::anyhow!("{}", e))
+    }
+}
+
+/// Turn a client away with a 421 reply instead of the greeting
+async fn refuse<S: AsyncRead + AsyncWrite + Unpin>(mut client: S, reason: &str) {
+    let _ = client.write_all(format!("421 {}\r\n", reason).as_bytes()).await;
+    let _ = client.shutdown().await;
+}
+
+/// Both ends of a new TCP connection on `local`
+async fn local_pair(local: SocketAddr) -> std::io::Result<(TcpStream, TcpStream)> {
+    let listener = tokio::net::TcpListener::bind(local).await?;
+    let (connected, (accepted, peer)) = tokio::try_join!(TcpStream::connect(listener.local_addr()?), listener.accept())?;
+    // Someone else could have connected to the port first
+    if peer != connected.local_addr()? {
+        return Err(std::io::Error::new(ErrorKind::ConnectionRefused, format!("unexpected relay connection from {}", peer)));
+    }
+    connected.set_nodelay(true)?;
+    accepted.set_nodelay(true)?;
+    Ok((connected, accepted))
+}
+
+/// Accept control connections on `listener` until the server stops, then wait for the open ones to
+/// close. `tls` makes it an implicit FTPS listener.
+async fn accept_connections(
+    listener: TcpListener,
+    services: Arc<Services>,
+    tls: Option<TlsAcceptor>,
+    mut stop: watch::Receiver<bool>,
+) {
+    let listener = match listener.set_nonblocking(true).and_then(|_| tokio::net::TcpListener::from_std(listener)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to start the FTP listener: {}", e);
+            return;
+        }
+    };
+
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    let _ = stream.set_nodelay(true);
+                    let (services, tls) = (services.clone(), tls.clone());
+                    connections.spawn(async move { services.serve(stream, peer, tls).await });
+                }
+                Err(e) => {
+                    // Usually out of file descriptors, give connections a moment to close
+                    warn!("Failed to accept a connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            },
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            // Also stops when the handle is dropped
+            _ = stop.changed() => break,
+        }
+    }
+    // Release the port now, the kernel would otherwise keep queueing connections nobody accepts
+    drop(listener);
+    while connections.join_next().await.is_some() {}
+}
+
+/// A running server; dropping it stops accepting connections, leaves the open ones to their clients and
+/// router port mappings to expire
+pub struct ServerHandle {
+    config: ServerConfig,
+    bound_addresses: Vec<IpAddr>,
+    tasks: Vec<JoinHandle<()>>,
+    stop: watch::Sender<bool>,
+    sessions: SessionRegistry,
+    progress_task: JoinHandle<()>,
+    pasv_available: usize,
+    /// Description of the address advertised in PASV replies, `None` for the connection address
+    passive_address: Option<String>,
+    /// Mappings on the router and the task renewing them
+    port_mapping: Option<(PortMapping, JoinHandle<()>)>,
+    port_mapping_error: Option<String>,
+}
+
+impl ServerHandle {
+    /// Addresses being served; `0.0.0.0` may be served by a dual-stack `::` listener
+    pub fn bound_addresses(&self) -> &[IpAddr] {
+        &self.bound_addresses
+    }
+
+    /// Open connections and the sessions logged in on them
+    pub fn sessions(&self) -> &SessionRegistry {
+        &self.sessions
+    }
+
+    /// Connection details for display
+    pub fn server_info(&self) -> ServerInfo {
+        let config = &self.config;
+        ServerInfo {
+            successful_bindings: self.bound_addresses.clone(),
+            port: config.port,
+            pasv_range: config.pasv_range.clone(),
+            pasv_available: self.pasv_available,
+            passive_address: self.passive_address.clone(),
+            port_mapping: self.port_mapping.as_ref().map(|(mapping, _)| mapping.describe()),
+            port_mapping_error: self.port_mapping_error.clone(),
+            directory: config.directory.clone(),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            accounts: config.accounts.iter().map(|a| a.summary()).collect(),
+            tls: config.tls.as_ref().map(|t| t.describe()),
+            tls_fingerprint: config.tls.as_ref().map(|t| t.fingerprint.clone()),
+            access: config.access_mode.describe(),
+            anonymous: config.anonymous.as_ref().map(|a| a.describe()),
+            rate_limits: config.rate_limits.describe(),
+            connection_limits: config.connection_limits.describe(),
+            login_protection: config.login_protection.describe(),
+            ip_rules: config.ip_rules.describe(),
+        }
+    }
+
+    /// Stop accepting connections, wait for active transfers to finish up to the shutdown timeout,
+    /// then close every connection
+    pub async fn shutdown(self) {
+        info!(
+            "Stopping FTP server, waiting up to {}s for active transfers to finish",
+            self.config.shutdown_timeout.as_secs()
+        );
+        let _ = self.stop.send(true);
+
+        let deadline = Instant::now() + self.config.shutdown_timeout;
+        let mut changes = self.sessions.subscribe();
+        while self.sessions.transfer_count() > 0 {
+            match tokio::time::timeout_at(deadline, changes.changed()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => break,
+                Err(_) => {
+                    warn!("Transfers still running after {}s, closing them", self.config.shutdown_timeout.as_secs());
+                    break;
+                }
+            }
+        }
+        self.sessions.close_connections();
+
+        // Closing is immediate, the margin only guards against a libunftp session that hangs
+        let deadline = Instant::now() + Duration::from_secs(5);
+        for mut task in self.tasks {
+            if tokio::time::timeout_at(deadline, &mut task).await.is_err() {
+                warn!("FTP connections did not close in time, aborting them");
+                task.abort();
+            }
+        }
+
+        self.progress_task.abort();
+        if let Some((mapping, renewal)) = self.port_mapping {
+            renewal.abort();
+            mapping.remove().await;
+        }
+        self.sessions.clear();
+        info!("FTP server stopped");
+    }
+}
+
+/// Start listening on every configured address. Must be called from within a Tokio runtime.
+pub async fn start(mut config: ServerConfig) -> Result<ServerHandle> {
+    if !config.directory.exists() {
+        return Err(anyhow::anyhow!("Directory '{}' does not exist", config.directory.display()));
+    }
+
+    if config.ip_rules.lan_only && config.port_mapping.method != PortMappingMethod::Off {
+        return Err(anyhow::anyhow!(
+            "LAN only mode refuses clients from the internet, turn off port mapping to use it"
+        ));
+    }
+
+    users::ensure_home_dirs(&config.directory, &config.accounts)?;
+    if let Some(home) = config.anonymous.as_ref().and_then(|a| a.home.as_ref()) {
+        std::fs::create_dir_all(config.directory.join(home))?;
+    }
+
+    // Every listener shares the same authenticator so only the configured credentials are accepted
+    let sessions = SessionRegistry::new(config.connection_limits, config.login_protection);
+    let audit = match &config.audit_log {
+        Some(path) => {
+            let audit = AuditLog::open(path, sessions.clone())?;
+            info!("Writing audit log to {}", audit.path().display());
+            Some(audit)
+        }
+        None => None,
+    };
+    let port = config.port;
+    let implicit_port = config.tls.as_ref().and_then(|tls| tls.implicit_port);
+    if implicit_port == Some(port) {
+        return Err(anyhow::anyhow!("The implicit FTPS port must differ from the main port {}", port));
+    }
+    if let Some(implicit_port) = implicit_port
+        && config.pasv_range.contains(&implicit_port)
+    {
+        warn!(
+            "Implicit FTPS port {} is inside the passive port range and can't be used for transfers",
+            implicit_port
+        );
+    }
+    let acceptor = match (&config.tls, implicit_port) {
+        (Some(tls), Some(_)) => Some(tls.acceptor()?),
+        _ => None,
+    };
+
+    let listen_addresses = config.listen_addresses()?;
+    let (listeners, bound_addresses) = bind_listeners(&listen_addresses, port)?;
+    let implicit_listeners = match implicit_port {
+        Some(implicit_port) => bind_listeners(&listen_addresses, implicit_port)?.0,
+        None => Vec::new(),
+    };
+
+    // libunftp binds a passive port per transfer and only fails the transfer when none is free
+    let pasv_total = config.pasv_range.clone().count();
+    let mut pasv_available = available_ports(&config.pasv_range, &bound_addresses);
+    if pasv_available == 0 {
+        return Err(anyhow::anyhow!(
+            "None of the passive ports {} to {} can be bound, they are in use or need administrator rights",
+            config.pasv_range.start(),
+            config.pasv_range.end()
+        ));
+    }
+    if pasv_available < pasv_total {
+        warn!(
+            "Only {}{} of {} passive ports are available, the rest are in use by other programs",
+            if pasv_total > MAX_PORT_PROBES { "about " } else { "" },
+            pasv_available,
+            pasv_total
+        );
+    }
+
+    // Map the ports before resolving the passive host, `auto` can use the address the router reports.
+    // Failing to map isn't fatal, the ports may be forwarded by hand.
+    let mut mapped_ports = vec![port];
+    mapped_ports.extend(implicit_port);
+    mapped_ports.extend(config.pasv_range.clone().filter(|p| *p != port && Some(*p) != implicit_port));
+    let (port_mapping, port_mapping_error) = match config.port_mapping.map(&mapped_ports).await {
+        Ok(mapping) => (mapping, None),
+        Err(e) => {
+            warn!("Failed to map ports on the router: {:#}", e);
+            (None, Some(format!("{:#}", e)))
+        }
+    };
+
+    // libunftp may hand out any port of the range, so only offer the ones the router forwards
+    if let Some(mapping) = &port_mapping {
+        match mapping.mapped_range(&config.pasv_range) {
+            Some(range) if range != config.pasv_range => {
+                warn!(
+                    "The router only forwards passive ports {} to {}, passive mode is limited to them",
+                    range.start(),
+                    range.end()
+                );
+                pasv_available = pasv_available.min(range.len());
+                config.pasv_range = range;
+            }
+            Some(_) => {}
+            None => warn!(
+                "The router forwards none of the passive ports, forward {} to {} by hand",
+                config.pasv_range.start(),
+                config.pasv_range.end()
+            ),
+        }
+    }
+
+    if port_mapping.is_some() && config.passive_host == PassiveHostConfig::FromConnection {
+        warn!(
+            "Passive replies advertise the address clients connect to, \
+             set the passive host to auto for clients on the internet"
+        );
+    }
+
+    let (stop, stop_rx) = watch::channel(false);
+    let router_address = port_mapping.as_ref().and_then(PortMapping::public_address);
+    let services = async {
+        let (passive_host, passive_address) = config.passive_host.resolve(router_address).await?;
+        let services = Services {
+            config: config.clone(),
+            authenticator: Arc::new(config.authenticator().with_sessions(sessions.clone())),
+            sessions,
+            audit,
+            throttle: Throttle::new(config.rate_limits),
+            passive_host,
+        };
+        // Catch configuration errors now rather than on the first connection
+        services.build_server(0, config.bind_addr, false)?;
+        Ok::<_, anyhow::Error>((Arc::new(services), passive_address))
+    };
+    let (services, passive_address) = match services.await {
+        Ok(started) => started,
+        Err(e) => {
+            if let Some(mapping) = &port_mapping {
+                mapping.remove().await;
+            }
+            return Err(e);
+        }
+    };
+
+    let tasks = listeners
+        .into_iter()
+        .map(|listener| (listener, None))
+        .chain(implicit_listeners.into_iter().map(|listener| (listener, acceptor.clone())))
+        .map(|(listener, tls)| tokio::spawn(accept_connections(listener, services.clone(), tls, stop_rx.clone())))
+        .collect();
+
+    info!("FTP server started successfully on {} address(es)", bound_addresses.len());
+
+    // Transfer progress and the time left on bans change without any event, so wake session watchers
+    // while data is moving or addresses are banned. Also report when transfers use up the passive ports.
+    let progress_sessions = services.sessions.clone();
+    let progress_task = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        let mut exhausted = false;
+        loop {
+            interval.tick().await;
+            progress_sessions.bans().expire();
+
+            let transfers = progress_sessions.transfer_count();
+            if transfers >= pasv_available && !exhausted {
+                warn!(
+                    "All {} available passive ports are busy with transfers, new passive transfers fail until one finishes",
+                    pasv_available
+                );
+            }
+            exhausted = transfers >= pasv_available;
+
+            if transfers > 0 || !progress_sessions.bans().is_empty() {
+                progress_sessions.notify();
+            }
+        }
+    });
+
+    Ok(ServerHandle {
+        config,
+        bound_addresses,
+        tasks,
+        stop,
+        sessions: services.sessions.clone(),
+        progress_task,
+        pasv_available,
+        passive_address,
+        port_mapping: port_mapping.map(|mapping| {
+            let renewal = mapping.spawn_renewal();
+            (mapping, renewal)
+        }),
+        port_mapping_error,
+    })
+}
+
+/// Bind `port` on every address and keep the listeners, the connections are accepted here and handed
+/// to libunftp. Returns the listeners and the addresses they serve. `::` has to come before `0.0.0.0`:
+/// where the system makes it dual-stack it takes IPv4 as well and `0.0.0.0` then clashes with it,
+/// which leaves IPv4 covered.
+fn bind_listeners(addresses: &[IpAddr], port: u16) -> Result<(Vec<TcpListener>, Vec<IpAddr>)> {
+    let mut listeners = Vec::new();
+    let mut bound_addresses = Vec::new();
+    let mut failures = Vec::new();
+    for addr in addresses.iter().copied() {
+        let socket_addr = SocketAddr::new(addr, port);
+        match TcpListener::bind(socket_addr) {
+            Ok(listener) => {
+                info!("Successfully bound to {}", socket_addr);
+                listeners.push(listener);
+                bound_addresses.push(addr);
+            }
+            Err(e) if e.kind() == ErrorKind::AddrInUse
+                && addr == IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+                && bound_addresses.contains(&IpAddr::V6(Ipv6Addr::UNSPECIFIED)) =>
+            {
+                info!("IPv4 clients are served by the dual-stack listener on [::]:{}", port);
+                bound_addresses.push(addr);
+            }
+            Err(e) => {
+                warn!("Failed to bind to {}: {}", socket_addr, e);
+                failures.push(format!("{}: {}", socket_addr, e));
+            }
+        }
+    }
+
+    if listeners.is_empty() {
+        return Err(anyhow::anyhow!("Failed to bind to port {} ({})", port, failures.join("; ")));
+    }
+    Ok((listeners, bound_addresses))
+}
+
+/// Count the ports in `range` that can currently be bound on one address of each family in
+/// `addresses`. Ranges wider than `MAX_PORT_PROBES` are sampled and the count is an estimate.
+fn available_ports(range: &RangeInclusive<u16>, addresses: &[IpAddr]) -> usize {
+    let ipv4 = addresses.iter().find(|addr| addr.is_ipv4());
+    let ipv6 = addresses.iter().find(|addr| addr.is_ipv6());
+    let probes = probe_ports(range);
+    let free = probes
+        .iter()
+        .filter(|port| {
+            ipv4.into_iter()
+                .chain(ipv6)
+                .all(|addr| TcpListener::bind(SocketAddr::new(*addr, **port)).is_ok())
+        })
+        .count();
+    if free == probes.len() {
+        range.len()
+    } else {
+        // Don't let the estimate round a partly free range down to nothing
+        (free * range.len() / probes.len()).max(free.min(1))
+    }
+}
+
+/// The ports of `range` to probe, all of them or `MAX_PORT_PROBES` evenly spaced ones
+fn probe_ports(range: &RangeInclusive<u16>) -> Vec<u16> {
+    let total = range.len();
+    let count = total.min(MAX_PORT_PROBES);
+    (0..count)
+        .map(|i| *range.start() + (i * total / count) as u16)
+        .collect()
+}
+
+/// Parse a passive port range such as `30000-30100`. Any width is allowed; ranges that include
+/// privileged ports or the control port are accepted with a warning.
+pub fn parse_pasv_range(range: &str, control_port: u16) -> Result<RangeInclusive<u16>> {
+    let invalid = || anyhow::anyhow!("Invalid passive port range '{}', use start-end (e.g. 30000-30100)", range.trim());
+    let (start, end) = range.trim().split_once('-').ok_or_else(invalid)?;
+    let start: u16 = start.trim().parse().map_err(|_| invalid())?;
+    let end: u16 = end.trim().parse().map_err(|_| invalid())?;
+
+    if start == 0 {
+        return Err(anyhow::anyhow!("Passive ports must be between 1 and 65535"));
+    }
+    if start > end {
+        return Err(anyhow::anyhow!("Passive port range start must be less than or equal to end"));
+    }
+
+    if start < 1024 {
+        warn!(
+            "Passive port range {}-{} includes privileged ports below 1024, which usually need administrator rights",
+            start, end
+        );
+    }
+    if (start..=end).contains(&control_port) {
+        warn!(
+            "Control port {} is inside the passive port range {}-{} and can't be used for transfers",
+            control_port, start, end
+        );
+    }
+
+    Ok(start..=end)
+}
+
+/// Parse a bind address, accepting IPv6 addresses with or without brackets
+pub fn parse_bind_address(bind: &str) -> Result<IpAddr> {
+    let cleaned = bind.trim().trim_start_matches('[').trim_end_matches(']');
+    cleaned
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid bind address '{}'", bind.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::{CertificateDer, ServerName};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+    use tokio_rustls::TlsConnector;
+
+    fn free_port() -> u16 {
+        TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port()
+    }
+
+    /// A fresh directory to serve, unique to `name` and this test run
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("sixftp-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn test_config(directory: PathBuf) -> ServerConfig {
+        let pasv_port = free_port();
+        ServerConfig {
+            directory,
+            port: free_port(),
+            bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            pasv_range: pasv_port..=pasv_port,
+            passive_host: PassiveHostConfig::default(),
+            port_mapping: PortMappingConfig::default(),
+            username: "user".to_string(),
+            password: "secret".to_string(),
+            accounts: Vec::new(),
+            anonymous: None,
+            access_mode: AccessMode::default(),
+            tls: None,
+            shutdown_timeout: Duration::from_secs(1),
+            audit_log: None,
+            rate_limits: RateLimits::default(),
+            connection_limits: ConnectionLimits::default(),
+            login_protection: LoginProtection::default(),
+            ip_rules: IpRules::default(),
+        }
+    }
+
+    async fn wait_for(what: &str, condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn releases_the_port_while_connections_drain() {
+        let config = test_config(test_directory("drain"));
+        let address = SocketAddr::new(config.bind_addr, config.port);
+        let handle = start(config).await.unwrap();
+        let sessions = handle.sessions().clone();
+
+        let client = TcpStream::connect(address).await.unwrap();
+        wait_for("the connection to be accepted", || sessions.connection_count() == 1).await;
+
+        // Dropping the handle stops the listeners and leaves the open connection to its client
+        drop(handle);
+        // A connection queued by the kernel succeeds or, with a full backlog, hangs
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !matches!(
+            tokio::time::timeout(Duration::from_millis(500), TcpStream::connect(address)).await,
+            Ok(Err(_))
+        ) {
+            assert!(Instant::now() < deadline, "the control port still takes connections");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(sessions.connection_count(), 1);
+
+        drop(client);
+        wait_for("the connection to close", || sessions.connection_count() == 0).await;
+    }
+
+    #[test]
+    fn samples_wide_passive_ranges() {
+        let probes = probe_ports(&(1024..=65535));
+        assert_eq!(probes.len(), MAX_PORT_PROBES);
+        assert_eq!(probes[0], 1024);
+        assert!(probes.windows(2).all(|pair| pair[1] - pair[0] == 252));
+        assert_eq!(probe_ports(&(30000..=30009)), (30000..=30009).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn counts_ports_free_on_every_address_family() {
+        let taken = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let addresses = [IpAddr::V4(Ipv4Addr::LOCALHOST)];
+        assert_eq!(available_ports(&(port..=port), &addresses), 0);
+        drop(taken);
+        assert_eq!(available_ports(&(port..=port), &addresses), 1);
+
+        // A port taken on IPv6 only is still unavailable when both families are served
+        if let Ok(taken) = TcpListener::bind((Ipv6Addr::LOCALHOST, port)) {
+            let addresses = [IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)];
+            assert_eq!(available_ports(&(port..=port), &addresses), 0);
+            drop(taken);
+        }
+    }
+
+    /// Client side of an FTP control connection
+    struct Control<S> {
+        stream: BufReader<S>,
+    }
+
+    impl<S: AsyncRead + AsyncWrite + Unpin> Control<S> {
+        /// Read a reply, skipping the continuation lines of multi-line ones
+        async fn reply(&mut self) -> (u16, String) {
+            tokio::time::timeout(Duration::from_secs(10), self.read_reply()).await.expect("no reply within 10s")
+        }
+
+        async fn read_reply(&mut self) -> (u16, String) {
+            let mut line = String::new();
+            self.stream.read_line(&mut line).await.unwrap();
+            let code = line.get(..3).and_then(|code| code.parse().ok()).unwrap_or_else(|| panic!("bad reply {:?}", line));
+            if line.as_bytes().get(3) == Some(&b'-') {
+                loop {
+                    let mut next = String::new();
+                    self.stream.read_line(&mut next).await.unwrap();
+                    assert!(!next.is_empty(), "connection closed in a multi-line reply");
+                    if next.starts_with(&format!("{} ", code)) {
+                        break;
+                    }
+                }
+            }
+            (code, line.trim_end().to_string())
+        }
+
+        async fn send(&mut self, command: &str) {
+            self.stream.get_mut().write_all(format!("{}\r\n", command).as_bytes()).await.unwrap();
+            self.stream.get_mut().flush().await.unwrap();
+        }
+
+        async fn command(&mut self, command: &str) -> (u16, String) {
+            self.send(command).await;
+            self.reply().await
+        }
+    }
+
+    fn tls_connector(certificate: &CertificateDer<'_>) -> TlsConnector {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(certificate.clone().into_owned()).unwrap();
+        let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        TlsConnector::from(Arc::new(config))
+    }
+
+    /// The data connection address in a `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)` reply
+    fn passive_address(reply: &str) -> SocketAddr {
+        let start = reply.find('(').unwrap() + 1;
+        let end = reply.find(')').unwrap();
+        let numbers: Vec<u8> = reply[start..end].split(',').map(|n| n.trim().parse().unwrap()).collect();
+        let ip = Ipv4Addr::new(numbers[0], numbers[1], numbers[2], numbers[3]);
+        SocketAddr::new(IpAddr::V4(ip), u16::from_be_bytes([numbers[4], numbers[5]]))
+    }
+
+    #[tokio::test]
+    async fn implicit_ftps_transfers_over_tls() {
+        let directory = test_directory("implicit-ftps");
+        std::fs::write(directory.join("hello.txt"), "hello over TLS").unwrap();
+        let keys = test_directory("implicit-ftps-keys");
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(keys.join("cert.pem"), certified.cert.pem()).unwrap();
+        std::fs::write(keys.join("key.pem"), certified.key_pair.serialize_pem()).unwrap();
+
+        // TLS is required, so the transfer below only works with a protected data channel
+        let implicit_port = free_port();
+        let mut config = test_config(directory);
+        config.tls = Some(
+            TlsConfig::new(keys.join("cert.pem"), keys.join("key.pem"), true)
+                .unwrap()
+                .with_implicit_port(Some(implicit_port)),
+        );
+        let handle = start(config).await.unwrap();
+
+        let connector = tls_connector(certified.cert.der());
+        let localhost = ServerName::try_from("localhost").unwrap();
+        let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, implicit_port)).await.unwrap();
+        let stream = connector.connect(localhost.clone(), stream).await.unwrap();
+        let mut control = Control { stream: BufReader::new(stream) };
+
+        assert_eq!(control.reply().await.0, 220);
+        assert_eq!(control.command("USER user").await.0, 331);
+        assert_eq!(control.command("PASS secret").await.0, 230);
+        assert_eq!(control.command("PBSZ 0").await.0, 200);
+        assert_eq!(control.command("PROT P").await.0, 200);
+        assert_eq!(control.command("TYPE I").await.0, 200);
+        let (code, reply) = control.command("PASV").await;
+        assert_eq!(code, 227, "{}", reply);
+
+        let data = TcpStream::connect(passive_address(&reply)).await.unwrap();
+        control.send("RETR hello.txt").await;
+        let mut data = connector.connect(localhost, data).await.unwrap();
+        let mut received = String::new();
+        data.read_to_string(&mut received).await.unwrap();
+        assert_eq!(received, "hello over TLS");
+        assert!(matches!(control.reply().await.0, 125 | 150));
+        assert_eq!(control.reply().await.0, 226);
+        assert_eq!(control.command("QUIT").await.0, 221);
+
+        handle.shutdown().await;
+    }
+}
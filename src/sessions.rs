@@ -0,0 +1,524 @@
+use anyhow::Result;
+use libunftp::notification::{EventMeta, PresenceEvent};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::{watch, Notify};
+use tracing::info;
+use crate::bans::{BanList, LoginProtection};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+#[derive(Debug)]
+struct Transfer {
+    direction: Direction,
+    path: String,
+    bytes: Arc<AtomicU64>,
+    /// Set when the session is kicked so the transfer stops mid-stream
+    aborted: Arc<AtomicBool>,
+    started: Instant,
+}
+
+#[derive(Debug)]
+struct Session {
+    remote: IpAddr,
+    /// Control connection the session logged in on
+    connection: u64,
+    username: String,
+    logged_in: Instant,
+    cwd: String,
+    trace_id: Option<String>,
+    transfer: Option<Transfer>,
+    /// How long the most recent finished transfer took, for the audit log
+    last_transfer: Option<Duration>,
+}
+
+/// Caps on concurrent control connections, `None` meaning unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    pub max: Option<usize>,
+    pub per_ip: Option<usize>,
+}
+
+impl ConnectionLimits {
+    /// Description for the server info banner, `None` when unlimited
+    pub fn describe(&self) -> Option<String> {
+        match (self.max, self.per_ip) {
+            (None, None) => None,
+            (Some(max), None) => Some(format!("{} clients", max)),
+            (None, Some(per_ip)) => Some(format!("{} clients per address", per_ip)),
+            (Some(max), Some(per_ip)) => Some(format!("{} clients, {} per address", max, per_ip)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct OpenConnection {
+    remote: IpAddr,
+    /// Wakes the connection's relay to close it
+    close: Arc<Notify>,
+}
+
+#[derive(Debug, Default)]
+struct Sessions {
+    next_id: u64,
+    sessions: BTreeMap<u64, Session>,
+    /// libunftp identifies sessions in its events by trace id
+    trace_ids: HashMap<String, u64>,
+    next_connection: u64,
+    /// Control connections, logged in or not
+    connections: HashMap<u64, OpenConnection>,
+    /// Kicked sessions by connection, refused by the storage backend while the connection closes
+    kicked: HashMap<u64, u64>,
+}
+
+/// Control connections and logged-in sessions of a running server, updated by the listeners, the
+/// authenticator, the storage backend and libunftp's presence events
+#[derive(Debug, Clone)]
+pub struct SessionRegistry {
+    sessions: Arc<Mutex<Sessions>>,
+    changed: Arc<watch::Sender<()>>,
+    limits: ConnectionLimits,
+    bans: BanList,
+}
+
+impl Default for SessionRegistry {
+    fn default() -> Self {
+        Self::new(ConnectionLimits::default(), LoginProtection::default())
+    }
+}
+
+impl SessionRegistry {
+    pub fn new(limits: ConnectionLimits, protection: LoginProtection) -> Self {
+        let changed = Arc::new(watch::Sender::new(()));
+        Self {
+            sessions: Arc::default(),
+            bans: BanList::new(protection, changed.clone()),
+            changed,
+            limits,
+        }
+    }
+
+    /// Addresses refused at login; changes to it also wake `subscribe`rs
+    pub fn bans(&self) -> &BanList {
+        &self.bans
+    }
+
+    pub fn limits(&self) -> ConnectionLimits {
+        self.limits
+    }
+
+    /// Admit a new control connection, or refuse it if the server or the client's address already
+    /// has the maximum number of connections. It counts until the returned guard is dropped.
+    pub fn connect(&self, remote: IpAddr) -> Result<Connection> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(max) = self.limits.max
+            && sessions.connections.len() >= max
+        {
+            return Err(anyhow::anyhow!("server is full ({} clients)", max));
+        }
+        if let Some(per_ip) = self.limits.per_ip
+            && sessions.connections.values().filter(|c| c.remote == remote).count() >= per_ip
+        {
+            return Err(anyhow::anyhow!("{} already has {} connections", remote, per_ip));
+        }
+
+        sessions.next_connection += 1;
+        let id = sessions.next_connection;
+        let close = Arc::new(Notify::new());
+        sessions.connections.insert(id, OpenConnection { remote, close: close.clone() });
+        drop(sessions);
+
+        self.notify();
+        Ok(Connection {
+            registry: self.clone(),
+            id,
+            close,
+        })
+    }
+
+    /// Number of open control connections, logged in or not
+    pub fn connection_count(&self) -> usize {
+        self.sessions.lock().unwrap().connections.len()
+    }
+
+    /// Close every control connection, e.g. once the shutdown grace period is over
+    pub fn close_connections(&self) {
+        for connection in self.sessions.lock().unwrap().connections.values() {
+            connection.close.notify_one();
+        }
+    }
+
+    /// Record a successful login on `connection` and return the new session's id
+    pub fn login(&self, username: &str, remote: IpAddr, connection: u64) -> Result<u64> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if !sessions.connections.contains_key(&connection) {
+            return Err(anyhow::anyhow!("the connection was closed"));
+        }
+
+        sessions.next_id += 1;
+        let id = sessions.next_id;
+        sessions.sessions.insert(id, Session {
+            remote,
+            connection,
+            username: username.to_string(),
+            logged_in: Instant::now(),
+            cwd: "/".to_string(),
+            trace_id: None,
+            transfer: None,
+            last_transfer: None,
+        });
+        drop(sessions);
+
+        self.notify();
+        Ok(id)
+    }
+
+    /// Whether the session was kicked; libunftp may still be running a command of it while its
+    /// connection closes
+    pub fn is_kicked(&self, id: u64) -> bool {
+        self.sessions.lock().unwrap().kicked.contains_key(&id)
+    }
+
+    /// Remote address of the session libunftp knows by `trace_id`
+    pub fn remote_of(&self, trace_id: &str) -> Option<IpAddr> {
+        let sessions = self.sessions.lock().unwrap();
+        let id = sessions.trace_ids.get(trace_id)?;
+        sessions.sessions.get(id).map(|s| s.remote)
+    }
+
+    /// Duration of the last finished transfer of the session libunftp knows by `trace_id`
+    pub fn last_transfer_duration(&self, trace_id: &str) -> Option<Duration> {
+        let sessions = self.sessions.lock().unwrap();
+        let id = sessions.trace_ids.get(trace_id)?;
+        sessions.sessions.get(id).and_then(|s| s.last_transfer)
+    }
+
+    /// Disconnect a session, and with `ban` every other connection from its address, refusing
+    /// further connections from it until the server stops. Returns `false` if there is no such session.
+    pub fn kick(&self, id: u64, ban: bool) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some((remote, connection)) = sessions.sessions.get(&id).map(|s| (s.remote, s.connection)) else {
+            return false;
+        };
+
+        let kicked: Vec<u64> = sessions
+            .sessions
+            .iter()
+            .filter(|(other, s)| **other == id || (ban && s.remote == remote))
+            .map(|(other, _)| *other)
+            .collect();
+        for kicked_id in &kicked {
+            if let Some(session) = sessions.sessions.remove(kicked_id) {
+                if let Some(transfer) = &session.transfer {
+                    transfer.aborted.store(true, Ordering::Relaxed);
+                }
+                sessions.kicked.insert(*kicked_id, session.connection);
+                info!("Kicked session {} ('{}' from {})", kicked_id, session.username, session.remote);
+            }
+        }
+        sessions.trace_ids.retain(|_, session| !kicked.contains(session));
+
+        // Closing the connection ends the client's session in libunftp as well
+        for (other, open) in &sessions.connections {
+            if *other == connection || (ban && open.remote == remote) {
+                open.close.notify_one();
+            }
+        }
+        drop(sessions);
+
+        if ban {
+            self.bans.ban(remote, "kicked and banned");
+        }
+        self.notify();
+        true
+    }
+
+    pub fn set_cwd(&self, id: u64, cwd: &str) {
+        if let Some(session) = self.sessions.lock().unwrap().sessions.get_mut(&id) {
+            session.cwd = cwd.to_string();
+        }
+        self.notify();
+    }
+
+    /// Track a transfer until the returned guard is dropped
+    pub fn start_transfer(&self, id: u64, direction: Direction, path: &str) -> TransferGuard {
+        let bytes = Arc::new(AtomicU64::new(0));
+        let aborted = Arc::new(AtomicBool::new(false));
+        if let Some(session) = self.sessions.lock().unwrap().sessions.get_mut(&id) {
+            session.transfer = Some(Transfer {
+                direction,
+                path: path.to_string(),
+                bytes: bytes.clone(),
+                aborted: aborted.clone(),
+                started: Instant::now(),
+            });
+        }
+        self.notify();
+
+        TransferGuard {
+            registry: self.clone(),
+            id,
+            bytes,
+            aborted,
+        }
+    }
+
+    /// Number of sessions that are transferring, to refresh progress and watch passive port usage
+    pub fn transfer_count(&self) -> usize {
+        self.sessions.lock().unwrap().sessions.values().filter(|s| s.transfer.is_some()).count()
+    }
+
+    pub fn snapshot(&self) -> Vec<SessionSnapshot> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .sessions
+            .iter()
+            .map(|(id, session)| SessionSnapshot {
+                id: *id,
+                remote: session.remote,
+                username: session.username.clone(),
+                online: session.logged_in.elapsed(),
+                cwd: session.cwd.clone(),
+                transfer: session.transfer.as_ref().map(|t| TransferSnapshot {
+                    direction: t.direction,
+                    path: t.path.clone(),
+                    bytes: t.bytes.load(Ordering::Relaxed),
+                    elapsed: t.started.elapsed(),
+                }),
+            })
+            .collect()
+    }
+
+    /// Receiver that is marked changed whenever the sessions change
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
+
+    /// Wake subscribers, e.g. to refresh transfer progress
+    pub fn notify(&self) {
+        self.changed.send_replace(());
+    }
+
+    pub fn clear(&self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.sessions.clear();
+        sessions.trace_ids.clear();
+        sessions.connections.clear();
+        sessions.kicked.clear();
+        drop(sessions);
+        self.bans.clear();
+        self.notify();
+    }
+
+    /// Forget a closed connection and any session still logged in on it
+    fn disconnect(&self, connection: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.connections.remove(&connection).is_none() {
+            return;
+        }
+        let closed: Vec<u64> = sessions
+            .sessions
+            .iter()
+            .filter(|(_, s)| s.connection == connection)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &closed {
+            sessions.sessions.remove(id);
+        }
+        sessions.trace_ids.retain(|_, session| !closed.contains(session));
+        sessions.kicked.retain(|_, kicked| *kicked != connection);
+        drop(sessions);
+        self.notify();
+    }
+
+    fn finish_transfer(&self, id: u64, bytes: &Arc<AtomicU64>) {
+        if let Some(session) = self.sessions.lock().unwrap().sessions.get_mut(&id)
+            && session.transfer.as_ref().is_some_and(|t| Arc::ptr_eq(&t.bytes, bytes))
+            && let Some(transfer) = session.transfer.take()
+        {
+            session.last_transfer = Some(transfer.started.elapsed());
+        }
+        self.notify();
+    }
+}
+
+impl SessionRegistry {
+    /// Follow libunftp's presence events for the sessions of control connection `connection`
+    pub fn record_presence(&self, event: &PresenceEvent, meta: &EventMeta, connection: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        match event {
+            PresenceEvent::LoggedIn => {
+                // The authenticator registered the session on this connection just before this
+                // event, so link the oldest one of this user there that doesn't have a trace id yet
+                let id = sessions
+                    .sessions
+                    .iter_mut()
+                    .find(|(_, s)| s.connection == connection && s.username == meta.username && s.trace_id.is_none())
+                    .map(|(id, session)| {
+                        session.trace_id = Some(meta.trace_id.clone());
+                        *id
+                    });
+                if let Some(id) = id {
+                    sessions.trace_ids.insert(meta.trace_id.clone(), id);
+                }
+            }
+            PresenceEvent::LoggedOut => {
+                if let Some(id) = sessions.trace_ids.remove(&meta.trace_id) {
+                    sessions.sessions.remove(&id);
+                }
+            }
+        }
+        drop(sessions);
+        self.notify();
+    }
+}
+
+/// An admitted control connection, counted against the limits until dropped
+#[derive(Debug)]
+pub struct Connection {
+    registry: SessionRegistry,
+    id: u64,
+    close: Arc<Notify>,
+}
+
+impl Connection {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Resolves once the connection is kicked or the server closes it
+    pub async fn closed(&self) {
+        self.close.notified().await;
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.registry.disconnect(self.id);
+    }
+}
+
+/// Marks a session's transfer as finished when dropped
+#[derive(Debug)]
+pub struct TransferGuard {
+    registry: SessionRegistry,
+    id: u64,
+    bytes: Arc<AtomicU64>,
+    aborted: Arc<AtomicBool>,
+}
+
+impl Drop for TransferGuard {
+    fn drop(&mut self) {
+        self.registry.finish_transfer(self.id, &self.bytes);
+    }
+}
+
+/// Reader that counts the bytes passing through it for a session's transfer progress
+#[derive(Debug)]
+pub struct CountingReader<R> {
+    inner: R,
+    guard: TransferGuard,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(inner: R, guard: TransferGuard) -> Self {
+        Self { inner, guard }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        if self.guard.aborted.load(Ordering::Relaxed) {
+            return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "session was kicked")));
+        }
+
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        self.guard.bytes.fetch_add(read as u64, Ordering::Relaxed);
+        result
+    }
+}
+
+/// Point-in-time view of a session for display
+#[derive(Debug, Clone)]
+pub struct SessionSnapshot {
+    pub id: u64,
+    pub remote: IpAddr,
+    pub username: String,
+    /// Time since login
+    pub online: Duration,
+    pub cwd: String,
+    pub transfer: Option<TransferSnapshot>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TransferSnapshot {
+    pub direction: Direction,
+    pub path: String,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl SessionSnapshot {
+    /// One-line description, e.g. `alice from 192.168.1.20, online 3m 05s, in /photos`
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} from {}, online {}, in {}",
+            self.username,
+            self.remote,
+            format_duration(self.online),
+            self.cwd
+        );
+        if let Some(transfer) = &self.transfer {
+            let action = match transfer.direction {
+                Direction::Upload => "uploading",
+                Direction::Download => "downloading",
+            };
+            let seconds = transfer.elapsed.as_secs_f64().max(0.001);
+            summary.push_str(&format!(
+                ", {} {} ({}, {}/s)",
+                action,
+                transfer.path,
+                format_bytes(transfer.bytes),
+                format_bytes((transfer.bytes as f64 / seconds) as u64)
+            ));
+        }
+        summary
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..60 => format!("{}s", seconds),
+        60..3600 => format!("{}m {:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
@@ -0,0 +1,315 @@
+use async_trait::async_trait;
+use libunftp::storage::{self, ErrorKind, Fileinfo, Metadata, StorageBackend};
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+use unftp_sbe_fs::Filesystem;
+use crate::auth::ConnectionAuthenticator;
+use crate::sessions::{CountingReader, Direction, SessionRegistry};
+use crate::throttle::Throttle;
+use crate::users::{normalize_path, FtpUser};
+
+type Meta = <Filesystem as StorageBackend<FtpUser>>::Metadata;
+
+/// Server-wide restrictions applied on top of each user's own permissions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AccessMode {
+    #[default]
+    ReadWrite,
+    /// Listings and downloads only; STOR, APPE, DELE, MKD, RMD and RNFR/RNTO are refused
+    ReadOnly,
+    /// Upload-only: new files can be stored, but nothing can be listed, downloaded or overwritten
+    DropBox,
+}
+
+impl AccessMode {
+    /// Description for the server info banner, `None` for the unrestricted default
+    pub fn describe(&self) -> Option<String> {
+        match self {
+            AccessMode::ReadWrite => None,
+            AccessMode::ReadOnly => Some("read-only (uploads, deletes and renames are refused)".to_string()),
+            AccessMode::DropBox => Some("drop box (uploads of new files only, listings are empty)".to_string()),
+        }
+    }
+}
+
+/// Storage backend wrapping `unftp_sbe_fs::Filesystem` that confines each user
+/// to their home directory and enforces their read/write permissions and rate limits
+#[derive(Debug, Clone)]
+pub struct SixFs {
+    inner: Arc<Filesystem>,
+    /// The served directory, drop box uploads are written here directly
+    root: PathBuf,
+    access_mode: AccessMode,
+    sessions: SessionRegistry,
+    throttle: Throttle,
+}
+
+impl SixFs {
+    pub fn new(root: PathBuf, access_mode: AccessMode, sessions: SessionRegistry, throttle: Throttle) -> std::io::Result<Self> {
+        Ok(Self {
+            inner: Arc::new(Filesystem::new(root.clone())?),
+            root,
+            access_mode,
+            sessions,
+            throttle,
+        })
+    }
+
+    /// Map a client path onto the served directory, rooted at the user's home
+    fn resolve<P: AsRef<Path>>(user: &FtpUser, path: P) -> storage::Result<PathBuf> {
+        let relative = normalize_path(path.as_ref()).ok_or(ErrorKind::PermissionDenied)?;
+        let resolved = match &user.home {
+            Some(home) => normalize_path(home).ok_or(ErrorKind::PermissionDenied)?.join(relative),
+            None => relative,
+        };
+        Ok(Path::new("/").join(resolved))
+    }
+
+    /// Refuse everything once the session has been kicked
+    fn check_session(&self, user: &FtpUser) -> storage::Result<()> {
+        match user.session {
+            Some(id) if self.sessions.is_kicked(id) => Err(ErrorKind::ConnectionClosed.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_read(&self, user: &FtpUser) -> storage::Result<()> {
+        if user.read && self.access_mode != AccessMode::DropBox {
+            Ok(())
+        } else {
+            Err(ErrorKind::PermissionDenied.into())
+        }
+    }
+
+    /// Store a drop box upload at the resolved `path`. The file is created exclusively, so of two
+    /// uploads of the same name only the first is stored and the other can't replace it.
+    async fn store_new<R: AsyncRead + Unpin>(&self, path: &Path, mut input: R) -> storage::Result<u64> {
+        let local = self.root.join(path.strip_prefix("/").unwrap_or(path));
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&local)
+            .await
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::AlreadyExists => ErrorKind::PermissionDenied.into(),
+                std::io::ErrorKind::NotFound => ErrorKind::PermanentDirectoryNotAvailable.into(),
+                _ => storage::Error::new(ErrorKind::LocalError, e),
+            })?;
+        let written = tokio::io::copy(&mut input, &mut file)
+            .await
+            .map_err(|e| storage::Error::new(ErrorKind::LocalError, e))?;
+        file.flush().await.map_err(|e| storage::Error::new(ErrorKind::LocalError, e))?;
+        Ok(written)
+    }
+
+    /// Uploads are checked separately in `put`, this covers deletes, renames and directories
+    fn check_write(&self, user: &FtpUser) -> storage::Result<()> {
+        if user.write && self.access_mode == AccessMode::ReadWrite {
+            Ok(())
+        } else {
+            Err(ErrorKind::PermissionDenied.into())
+        }
+    }
+}
+
+/// Create a server builder that serves `fs` using the given authenticator. libunftp asks for a
+/// backend per connection, which shares the one checked when the server was built.
+pub fn server_builder(fs: SixFs, authenticator: Arc<ConnectionAuthenticator>) -> libunftp::ServerBuilder<SixFs, FtpUser> {
+    libunftp::ServerBuilder::with_authenticator(Box::new(move || fs.clone()), authenticator)
+}
+
+#[async_trait]
+impl StorageBackend<FtpUser> for SixFs {
+    type Metadata = Meta;
+
+    fn supported_features(&self) -> u32 {
+        StorageBackend::<FtpUser>::supported_features(self.inner.as_ref())
+    }
+
+    async fn metadata<P: AsRef<Path> + Send + Debug>(&self, user: &FtpUser, path: P) -> storage::Result<Self::Metadata> {
+        self.check_session(user)?;
+        let metadata = self.inner.metadata(user, Self::resolve(user, path)?).await?;
+
+        // Don't reveal which files other people dropped off
+        if self.access_mode == AccessMode::DropBox && !metadata.is_dir() {
+            return Err(ErrorKind::PermanentFileNotAvailable.into());
+        }
+        Ok(metadata)
+    }
+
+    async fn md5<P: AsRef<Path> + Send + Debug>(&self, user: &FtpUser, path: P) -> storage::Result<String> {
+        self.check_session(user)?;
+        self.check_read(user)?;
+        self.inner.md5(user, Self::resolve(user, path)?).await
+    }
+
+    async fn list<P: AsRef<Path> + Send + Debug>(&self, user: &FtpUser, path: P) -> storage::Result<Vec<Fileinfo<PathBuf, Self::Metadata>>>
+    where
+        <Self as StorageBackend<FtpUser>>::Metadata: storage::Metadata,
+    {
+        self.check_session(user)?;
+        if self.access_mode == AccessMode::DropBox {
+            return Ok(Vec::new());
+        }
+        self.check_read(user)?;
+        self.inner.list(user, Self::resolve(user, path)?).await
+    }
+
+    async fn get<P: AsRef<Path> + Send + Debug>(&self, user: &FtpUser, path: P, start_pos: u64) -> storage::Result<Box<dyn tokio::io::AsyncRead + Send + Sync + Unpin>> {
+        self.check_session(user)?;
+        self.check_read(user)?;
+        let display_path = path.as_ref().display().to_string();
+        let reader = self.inner.get(user, Self::resolve(user, path)?, start_pos).await?;
+        let reader = self.throttle.reader(reader, &user.username, Direction::Download);
+
+        match user.session {
+            Some(id) => {
+                let guard = self.sessions.start_transfer(id, Direction::Download, &display_path);
+                Ok(Box::new(CountingReader::new(reader, guard)))
+            }
+            None => Ok(Box::new(reader)),
+        }
+    }
+
+    async fn put<P: AsRef<Path> + Send + Debug, R: tokio::io::AsyncRead + Send + Sync + Unpin + 'static>(&self, user: &FtpUser, input: R, path: P, start_pos: u64) -> storage::Result<u64> {
+        self.check_session(user)?;
+        if !user.write || self.access_mode == AccessMode::ReadOnly {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+
+        let display_path = path.as_ref().display().to_string();
+        let path = Self::resolve(user, path)?;
+        // Appending, resuming or overwriting would touch someone else's upload
+        let drop_box = self.access_mode == AccessMode::DropBox;
+        if drop_box && start_pos > 0 {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+
+        let input = self.throttle.reader(input, &user.username, Direction::Upload);
+        match user.session {
+            Some(id) => {
+                let guard = self.sessions.start_transfer(id, Direction::Upload, &display_path);
+                let input = CountingReader::new(input, guard);
+                if drop_box {
+                    self.store_new(&path, input).await
+                } else {
+                    self.inner.put(user, input, path, start_pos).await
+                }
+            }
+            None if drop_box => self.store_new(&path, input).await,
+            None => self.inner.put(user, input, path, start_pos).await,
+        }
+    }
+
+    async fn del<P: AsRef<Path> + Send + Debug>(&self, user: &FtpUser, path: P) -> storage::Result<()> {
+        self.check_session(user)?;
+        self.check_write(user)?;
+        self.inner.del(user, Self::resolve(user, path)?).await
+    }
+
+    async fn mkd<P: AsRef<Path> + Send + Debug>(&self, user: &FtpUser, path: P) -> storage::Result<()> {
+        self.check_session(user)?;
+        self.check_write(user)?;
+        self.inner.mkd(user, Self::resolve(user, path)?).await
+    }
+
+    async fn rename<P: AsRef<Path> + Send + Debug>(&self, user: &FtpUser, from: P, to: P) -> storage::Result<()> {
+        self.check_session(user)?;
+        self.check_write(user)?;
+        self.inner.rename(user, Self::resolve(user, from)?, Self::resolve(user, to)?).await
+    }
+
+    async fn rmd<P: AsRef<Path> + Send + Debug>(&self, user: &FtpUser, path: P) -> storage::Result<()> {
+        self.check_session(user)?;
+        self.check_write(user)?;
+        self.inner.rmd(user, Self::resolve(user, path)?).await
+    }
+
+    async fn cwd<P: AsRef<Path> + Send + Debug>(&self, user: &FtpUser, path: P) -> storage::Result<()> {
+        self.check_session(user)?;
+        let display_path = path.as_ref().display().to_string();
+        self.inner.cwd(user, Self::resolve(user, path)?).await?;
+
+        if let Some(id) = user.session {
+            self.sessions.set_cwd(id, &display_path);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::bans::LoginProtection;
+    use crate::sessions::ConnectionLimits;
+
+    fn drop_box(name: &str) -> (SixFs, PathBuf) {
+        let root = std::env::temp_dir().join(format!("sixftp-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let sessions = SessionRegistry::new(ConnectionLimits::default(), LoginProtection::default());
+        let fs = SixFs::new(root.clone(), AccessMode::DropBox, sessions, Throttle::default()).unwrap();
+        (fs, root)
+    }
+
+    fn uploader() -> FtpUser {
+        FtpUser {
+            username: "user".to_string(),
+            home: None,
+            read: true,
+            write: true,
+            session: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_box_keeps_the_first_of_concurrent_uploads() {
+        let (fs, root) = drop_box("drop-box-race");
+        let user = uploader();
+        let (first, mut first_data) = tokio::io::duplex(64);
+        let (second, mut second_data) = tokio::io::duplex(64);
+
+        // Both uploads start before either has its data; the refused one has stopped reading by then
+        let send = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let _ = first_data.write_all(b"first upload").await;
+            let _ = second_data.write_all(b"second upload").await;
+            drop((first_data, second_data));
+        };
+        let (first, second, ()) = tokio::join!(
+            fs.put(&user, first, "/report.txt", 0),
+            fs.put(&user, second, "/report.txt", 0),
+            send
+        );
+
+        let (stored, refused) = match (first, second) {
+            (Ok(len), Err(e)) => ((len, "first upload"), e),
+            (Err(e), Ok(len)) => ((len, "second upload"), e),
+            (first, second) => panic!("expected exactly one upload to succeed: {:?}, {:?}", first, second),
+        };
+        assert_eq!(refused.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(stored.0, stored.1.len() as u64);
+        assert_eq!(std::fs::read_to_string(root.join("report.txt")).unwrap(), stored.1);
+    }
+
+    #[tokio::test]
+    async fn drop_box_refuses_overwrites_and_resumes() {
+        let (fs, root) = drop_box("drop-box-overwrite");
+        let user = uploader();
+        std::fs::write(root.join("existing.txt"), "kept").unwrap();
+        std::fs::create_dir(root.join("folder")).unwrap();
+
+        for (path, start_pos) in [("/existing.txt", 0), ("/folder", 0), ("/new.txt", 4)] {
+            let error = fs.put(&user, &b"replaced"[..], path, start_pos).await.unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::PermissionDenied, "{}", path);
+        }
+        assert_eq!(std::fs::read_to_string(root.join("existing.txt")).unwrap(), "kept");
+        assert!(!root.join("new.txt").exists());
+
+        assert_eq!(fs.put(&user, &b"new"[..], "/sub/../new.txt", 0).await.unwrap(), 3);
+        assert_eq!(std::fs::read_to_string(root.join("new.txt")).unwrap(), "new");
+    }
+}
@@ -0,0 +1,225 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::Sleep;
+use crate::sessions::{format_bytes, Direction};
+
+/// Upload and download limits in bytes per second, `None` meaning unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    /// Shared by all clients together
+    pub download: Option<u64>,
+    pub upload: Option<u64>,
+    /// Shared by all sessions of the same user
+    pub user_download: Option<u64>,
+    pub user_upload: Option<u64>,
+}
+
+impl RateLimits {
+    /// Description for the server info banner, `None` when nothing is limited
+    pub fn describe(&self) -> Option<String> {
+        let limits: Vec<String> = [
+            ("download", self.download),
+            ("upload", self.upload),
+            ("download per user", self.user_download),
+            ("upload per user", self.user_upload),
+        ]
+        .into_iter()
+        .filter_map(|(name, rate)| rate.map(|rate| format!("{} {}", name, format_rate(rate))))
+        .collect();
+
+        if limits.is_empty() { None } else { Some(limits.join(", ")) }
+    }
+}
+
+/// Parse a rate such as `5MiB/s`, `500K` or `1.5 MB/s` into bytes per second. `K`, `M` and `G`
+/// are binary (KiB, MiB, GiB), `KB`, `MB` and `GB` decimal. `0` or an empty string means unlimited.
+pub fn parse_rate(rate: &str) -> Result<Option<u64>> {
+    let trimmed = rate.trim();
+    let without_per_second = trimmed.strip_suffix("/s").unwrap_or(trimmed).trim_end();
+    if without_per_second.is_empty() {
+        return Ok(None);
+    }
+
+    let split = without_per_second
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(without_per_second.len());
+    let (number, unit) = without_per_second.split_at(split);
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        _ => return Err(anyhow::anyhow!("Invalid rate '{}', use e.g. 5MiB/s or 500KiB/s", rate.trim())),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid rate '{}', use e.g. 5MiB/s or 500KiB/s", rate.trim()))?;
+
+    let bytes = (number * multiplier as f64) as u64;
+    Ok(if bytes == 0 { None } else { Some(bytes) })
+}
+
+pub fn format_rate(bytes_per_second: u64) -> String {
+    format!("{}/s", format_bytes(bytes_per_second))
+}
+
+/// Token bucket holding up to one second worth of bytes
+#[derive(Debug, Clone)]
+struct RateLimiter {
+    rate: u64,
+    /// Bytes that may still be sent right away (negative when in debt) and when it was last updated
+    state: Arc<Mutex<(f64, Instant)>>,
+}
+
+impl RateLimiter {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            state: Arc::new(Mutex::new((rate as f64, Instant::now()))),
+        }
+    }
+
+    /// Account for `bytes` that were just transferred and return how long to pause before the next read
+    fn consume(&self, bytes: usize) -> Duration {
+        let rate = self.rate as f64;
+        let mut state = self.state.lock().unwrap();
+        let (available, updated) = &mut *state;
+
+        let now = Instant::now();
+        *available = (*available + now.duration_since(*updated).as_secs_f64() * rate).min(rate);
+        *updated = now;
+        *available -= bytes as f64;
+
+        if *available < 0.0 {
+            Duration::from_secs_f64(-*available / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// Rate limiters of a running server: one per direction for everyone, and one per direction for each user
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    limits: RateLimits,
+    download: Option<RateLimiter>,
+    upload: Option<RateLimiter>,
+    users: Arc<Mutex<HashMap<(String, Direction), RateLimiter>>>,
+}
+
+impl Throttle {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            download: limits.download.map(RateLimiter::new),
+            upload: limits.upload.map(RateLimiter::new),
+            users: Arc::default(),
+        }
+    }
+
+    /// Wrap a transfer's data stream so it respects the global and the user's limits
+    pub fn reader<R>(&self, inner: R, username: &str, direction: Direction) -> ThrottledReader<R> {
+        let (global, user_rate) = match direction {
+            Direction::Download => (&self.download, self.limits.user_download),
+            Direction::Upload => (&self.upload, self.limits.user_upload),
+        };
+
+        let mut limiters: Vec<RateLimiter> = global.iter().cloned().collect();
+        if let Some(rate) = user_rate {
+            let mut users = self.users.lock().unwrap();
+            let limiter = users
+                .entry((username.to_string(), direction))
+                .or_insert_with(|| RateLimiter::new(rate));
+            limiters.push(limiter.clone());
+        }
+
+        ThrottledReader {
+            inner,
+            limiters,
+            pause: None,
+        }
+    }
+}
+
+/// Reader that pauses between reads to keep its limiters' rates
+#[derive(Debug)]
+pub struct ThrottledReader<R> {
+    inner: R,
+    limiters: Vec<RateLimiter>,
+    pause: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ThrottledReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        if let Some(pause) = &mut self.pause {
+            if pause.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.pause = None;
+        }
+
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+
+        if read > 0 {
+            let wait = self.limiters.iter().map(|l| l.consume(read)).max().unwrap_or_default();
+            if !wait.is_zero() {
+                self.pause = Some(Box::pin(tokio::time::sleep(wait)));
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_binary_and_decimal_units() {
+        assert_eq!(parse_rate("100").unwrap(), Some(100));
+        assert_eq!(parse_rate("100B/s").unwrap(), Some(100));
+        assert_eq!(parse_rate("500K").unwrap(), Some(500 * 1024));
+        assert_eq!(parse_rate("500KiB/s").unwrap(), Some(500 * 1024));
+        assert_eq!(parse_rate("5MiB/s").unwrap(), Some(5 * 1024 * 1024));
+        assert_eq!(parse_rate("2G").unwrap(), Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_rate("500KB/s").unwrap(), Some(500_000));
+        assert_eq!(parse_rate("5MB").unwrap(), Some(5_000_000));
+        assert_eq!(parse_rate("2GB/s").unwrap(), Some(2_000_000_000));
+        assert_eq!(parse_rate("1.5MB/s").unwrap(), Some(1_500_000));
+    }
+
+    #[test]
+    fn ignores_case_and_whitespace() {
+        assert_eq!(parse_rate("5mib/s").unwrap(), Some(5 * 1024 * 1024));
+        assert_eq!(parse_rate("5 MB").unwrap(), Some(5_000_000));
+        assert_eq!(parse_rate("  1.5 mb /s  ").unwrap(), Some(1_500_000));
+        assert_eq!(parse_rate("500k").unwrap(), Some(500 * 1024));
+    }
+
+    #[test]
+    fn zero_and_empty_mean_unlimited() {
+        assert_eq!(parse_rate("0").unwrap(), None);
+        assert_eq!(parse_rate("0MiB/s").unwrap(), None);
+        assert_eq!(parse_rate("").unwrap(), None);
+        assert_eq!(parse_rate("   ").unwrap(), None);
+        assert_eq!(parse_rate("/s").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_rates() {
+        for rate in ["fast", "MB", "5 TB", "5MBps", "-5M", "1.2.3M", "5 M B", "."] {
+            assert!(parse_rate(rate).is_err(), "{:?} should be rejected", rate);
+        }
+    }
+}
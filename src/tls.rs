@@ -0,0 +1,172 @@
+use anyhow::{Context, Result};
+use libunftp::options::FtpsRequired;
+use libunftp::ServerBuilder;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+use tracing::info;
+use crate::config::write_private;
+use crate::network_info::get_network_ips;
+use crate::storage::SixFs;
+use crate::users::FtpUser;
+
+/// FTPS settings shared by every listener: explicit FTPS (AUTH TLS) on the main port, and implicit
+/// FTPS on a port of its own if one is set
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    /// Refuse plaintext logins and data transfers
+    pub required: bool,
+    /// SHA-256 fingerprint of the (first) certificate, for clients to verify
+    pub fingerprint: String,
+    pub self_signed: bool,
+    /// Port where connections start with a TLS handshake (traditionally 990)
+    pub implicit_port: Option<u16>,
+}
+
+impl TlsConfig {
+    /// Check the PEM files up front, libunftp would otherwise only fail when the server is built
+    pub fn new(cert_file: PathBuf, key_file: PathBuf, required: bool) -> Result<Self> {
+        check_pem(&key_file, "PRIVATE KEY", "private key")?;
+        let fingerprint = certificate_fingerprint(&cert_file)?;
+
+        Ok(Self {
+            cert_file,
+            key_file,
+            required,
+            fingerprint,
+            self_signed: false,
+            implicit_port: None,
+        })
+    }
+
+    /// Also accept implicit FTPS on `port`
+    pub fn with_implicit_port(mut self, port: Option<u16>) -> Self {
+        self.implicit_port = port;
+        self
+    }
+
+    /// Use a self-signed certificate cached in the config directory, generating it on first use
+    pub fn self_signed(required: bool) -> Result<Self> {
+        let dir = dirs::config_dir()
+            .ok_or_else(|| anyhow::anyhow!("Could not determine the config directory for the self-signed certificate"))?
+            .join("sixftp")
+            .join("self-signed");
+        let cert_file = dir.join("cert.pem");
+        let key_file = dir.join("key.pem");
+
+        // Reuse the cached certificate even if addresses changed, so the fingerprint stays stable
+        if !cert_file.exists() || !key_file.exists() {
+            generate_self_signed(&dir, &cert_file, &key_file)?;
+        } else {
+            info!("Using cached self-signed certificate {}", cert_file.display());
+        }
+
+        let mut tls = Self::new(cert_file, key_file, required)?;
+        tls.self_signed = true;
+        Ok(tls)
+    }
+
+    /// Short description for the server info banner
+    pub fn describe(&self) -> String {
+        let mut description = if self.required {
+            "explicit FTPS (AUTH TLS), required for login and transfers".to_string()
+        } else {
+            "explicit FTPS (AUTH TLS), optional".to_string()
+        };
+        if let Some(port) = self.implicit_port {
+            description.push_str(&format!(", implicit FTPS on port {}", port));
+        }
+        if self.self_signed {
+            description.push_str(", self-signed certificate");
+        }
+        description
+    }
+
+    /// TLS acceptor for the implicit FTPS listener
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let certificates = CertificateDer::pem_file_iter(&self.cert_file)
+            .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| anyhow::anyhow!("Failed to read TLS certificate '{}': {}", self.cert_file.display(), e))?;
+        let key = PrivateKeyDer::from_pem_file(&self.key_file)
+            .map_err(|e| anyhow::anyhow!("Failed to read TLS private key '{}': {}", self.key_file.display(), e))?;
+
+        // Name the provider explicitly, dependencies may enable more than one in rustls
+        let config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .context("Failed to set up TLS")?
+            .with_no_client_auth()
+            .with_single_cert(certificates, key)
+            .context("TLS certificate and private key don't work together")?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// Enable FTPS on a server builder if TLS is configured. With `implicit` the control connection is
+/// already encrypted by the implicit FTPS listener, which libunftp doesn't see, so only the data
+/// connections are left to it.
+pub fn configure(
+    builder: ServerBuilder<SixFs, FtpUser>,
+    tls: Option<&TlsConfig>,
+    implicit: bool,
+) -> ServerBuilder<SixFs, FtpUser> {
+    let Some(tls) = tls else {
+        return builder;
+    };
+
+    let builder = builder.ftps(tls.cert_file.clone(), tls.key_file.clone());
+    match (implicit, tls.required) {
+        (false, true) => builder.ftps_required(FtpsRequired::All, FtpsRequired::All),
+        (true, true) => builder.ftps_required(FtpsRequired::None, FtpsRequired::All),
+        (_, false) => builder,
+    }
+}
+
+fn generate_self_signed(dir: &Path, cert_file: &Path, key_file: &Path) -> Result<()> {
+    // Cover every address clients may use to reach us, plus the usual local names
+    let mut subject_alt_names = vec!["localhost".to_string()];
+    if let Ok(network_ips) = get_network_ips() {
+        subject_alt_names.extend(network_ips.ipv4.iter().map(|ip| ip.to_string()));
+        subject_alt_names.extend(network_ips.ipv6.iter().map(|ip| ip.to_string()));
+    }
+
+    let certified = rcgen::generate_simple_self_signed(subject_alt_names)
+        .context("Failed to generate self-signed certificate")?;
+
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create '{}'", dir.display()))?;
+    std::fs::write(cert_file, certified.cert.pem())
+        .with_context(|| format!("Failed to write '{}'", cert_file.display()))?;
+    write_private(key_file, &certified.key_pair.serialize_pem())?;
+
+    info!("Generated self-signed certificate {}", cert_file.display());
+    Ok(())
+}
+
+/// SHA-256 fingerprint of the first certificate in a PEM file, formatted as `AB:CD:...`
+fn certificate_fingerprint(cert_file: &Path) -> Result<String> {
+    let contents = std::fs::read_to_string(cert_file)
+        .with_context(|| format!("Failed to read TLS certificate '{}'", cert_file.display()))?;
+    let certificate = pem::parse_many(&contents)
+        .with_context(|| format!("Failed to parse TLS certificate '{}'", cert_file.display()))?
+        .into_iter()
+        .find(|p| p.tag() == "CERTIFICATE")
+        .ok_or_else(|| anyhow::anyhow!("'{}' does not contain a PEM encoded certificate", cert_file.display()))?;
+
+    let digest = Sha256::digest(certificate.contents());
+    Ok(digest.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":"))
+}
+
+fn check_pem(path: &Path, label: &str, what: &str) -> Result<()> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read TLS {} '{}'", what, path.display()))?;
+    // Accept any BEGIN line with the label, e.g. both "PRIVATE KEY" and "RSA PRIVATE KEY"
+    if !contents.lines().any(|l| l.starts_with("-----BEGIN ") && l.contains(label)) {
+        return Err(anyhow::anyhow!("'{}' does not contain a PEM encoded {}", path.display(), what));
+    }
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use anyhow::{Context, Result};
+use libunftp::auth::UserDetail;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+/// A single account entry in the users file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserAccount {
+    pub username: String,
+    /// bcrypt hash of the password (see `--hash-password`)
+    pub password_hash: String,
+    /// Home directory relative to the served root; the user cannot leave it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub home: Option<PathBuf>,
+    #[serde(default = "default_true")]
+    pub read: bool,
+    #[serde(default = "default_true")]
+    pub write: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl UserAccount {
+    pub fn new(username: &str, password: &str, home: Option<PathBuf>, read: bool, write: bool) -> Result<Self> {
+        Ok(Self {
+            username: username.to_string(),
+            password_hash: hash_password(password)?,
+            home,
+            read,
+            write,
+        })
+    }
+
+    /// Short description used in the server info banner, e.g. `alice (rw, home: /alice)`
+    pub fn summary(&self) -> String {
+        let mut access = String::new();
+        if self.read {
+            access.push('r');
+        }
+        if self.write {
+            access.push('w');
+        }
+        if access.is_empty() {
+            access.push_str("no access");
+        }
+
+        match &self.home {
+            Some(home) => format!("{} ({}, home: /{})", self.username, access, home.display()),
+            None => format!("{} ({})", self.username, access),
+        }
+    }
+}
+
+/// Users file listing every account allowed to log in, stored as TOML:
+///
+/// ```toml
+/// [[user]]
+/// username = "alice"
+/// password_hash = "$2b$12$..."
+/// home = "alice"
+/// read = true
+/// write = false
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UsersFile {
+    #[serde(default, rename = "user")]
+    pub users: Vec<UserAccount>,
+}
+
+impl UsersFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read users file '{}'", path.display()))?;
+        let users_file: UsersFile = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse users file '{}'", path.display()))?;
+        users_file.validate()?;
+        Ok(users_file)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        self.validate()?;
+        let contents = toml::to_string_pretty(self)?;
+        std::fs::write(path, contents)
+            .with_context(|| format!("Failed to write users file '{}'", path.display()))?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut seen = std::collections::HashSet::new();
+        for user in &self.users {
+            if user.username.is_empty() {
+                return Err(anyhow::anyhow!("Users file contains an account with an empty username"));
+            }
+            if !seen.insert(user.username.as_str()) {
+                return Err(anyhow::anyhow!("Duplicate user '{}' in users file", user.username));
+            }
+            if let Some(home) = &user.home
+                && normalize_path(home).is_none()
+            {
+                return Err(anyhow::anyhow!("Home directory of user '{}' escapes the served directory", user.username));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Create any missing home directories under the served root
+pub fn ensure_home_dirs(root: &Path, accounts: &[UserAccount]) -> Result<()> {
+    for home in accounts.iter().filter_map(|a| a.home.as_deref()) {
+        let home = normalize_path(home).unwrap_or_default();
+        std::fs::create_dir_all(root.join(&home))
+            .with_context(|| format!("Failed to create home directory '{}'", home.display()))?;
+    }
+    Ok(())
+}
+
+/// The logged-in user as seen by the storage backend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FtpUser {
+    pub username: String,
+    /// Home directory relative to the served root, `None` for the root itself
+    pub home: Option<PathBuf>,
+    pub read: bool,
+    pub write: bool,
+    /// Id of this login in the server's session registry
+    pub session: Option<u64>,
+}
+
+impl fmt::Display for FtpUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.username)
+    }
+}
+
+impl UserDetail for FtpUser {}
+
+pub fn hash_password(password: &str) -> Result<String> {
+    bcrypt::hash(password, bcrypt::DEFAULT_COST).context("Failed to hash password")
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    bcrypt::verify(password, hash).unwrap_or(false)
+}
+
+/// Resolve `.` and `..` in a relative path without touching the filesystem.
+/// Returns `None` if the path would climb above its starting point.
+pub fn normalize_path(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+    Some(normalized)
+}